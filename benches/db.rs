@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use mesagisto_client::db::Db;
+
+fn items() -> Vec<([u8; 4], Vec<u8>)> {
+  (0..1000u32)
+    .map(|i| (i.to_be_bytes(), format!("file_id_{}", i).into_bytes()))
+    .collect()
+}
+
+fn put_image_id(c: &mut Criterion) {
+  let mut group = c.benchmark_group("put_image_id");
+  group.bench_function("one by one", |b| {
+    b.iter_batched(
+      || (Db::open_in_memory(), items()),
+      // the db is returned so closing it isn't measured
+      |((db, dir), items)| {
+        for (uid, file_id) in items {
+          db.put_image_id(uid, file_id);
+        }
+        (db, dir)
+      },
+      BatchSize::PerIteration,
+    )
+  });
+  group.bench_function("many", |b| {
+    b.iter_batched(
+      || (Db::open_in_memory(), items()),
+      |((db, dir), items)| {
+        db.put_image_id_many(items).unwrap();
+        (db, dir)
+      },
+      BatchSize::PerIteration,
+    )
+  });
+  group.finish();
+}
+
+criterion_group!(benches, put_image_id);
+criterion_main!(benches);
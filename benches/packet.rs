@@ -0,0 +1,37 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mesagisto_client::{
+  cipher::CIPHER,
+  data::{
+    message::{Message, MessageType, Profile},
+    platforms::PLATFORM_TELEGRAM,
+    Packet,
+  },
+  EitherExt,
+};
+
+fn packet() -> Packet {
+  let profile = Profile {
+    id: Vec::from("user"),
+    username: None,
+    nick: None,
+  };
+  let chain = vec![MessageType::Text {
+    content: "this is text ".repeat(100),
+  }];
+  let message = Message::new(profile, 1, chain, "channel", PLATFORM_TELEGRAM);
+  Packet::from(message.to_left()).unwrap()
+}
+
+fn size(c: &mut Criterion) {
+  CIPHER.init(&"this is key".into());
+  let packet = packet();
+  let mut group = c.benchmark_group("packet size");
+  group.bench_function("size_bytes", |b| b.iter(|| black_box(&packet).size_bytes()));
+  group.bench_function("to_vec", |b| {
+    b.iter(|| serde_cbor::to_vec(black_box(&packet)).unwrap().len())
+  });
+  group.finish();
+}
+
+criterion_group!(benches, size);
+criterion_main!(benches);
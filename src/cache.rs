@@ -1,22 +1,154 @@
-use std::{panic, path::PathBuf, time::Duration};
+use std::{
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant, SystemTime},
+};
 
 use arcstr::ArcStr;
-use color_eyre::eyre::Result;
-use tracing::trace;
+use color_eyre::eyre::{eyre, Result};
+use dashmap::DashMap;
+use educe::Educe;
+use either::Either;
+use futures::future::BoxFuture;
+use lateinit::LateInit;
+use once_cell::sync::OnceCell;
+use sled::IVec;
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::{debug, error, trace};
 
 use crate::{
-  data::{events::Event, Packet},
-  net::NET,
+  data::{
+    events::{Event, RespondImage},
+    Packet,
+  },
+  db::{Db, DB},
+  error::{CacheError, DataError},
+  net::{ProgressSink, Validators, NET},
   res::RES,
   server::SERVER,
-  EitherExt,
+  EitherExt, OkExt, OptionExt,
 };
 
+// how long to wait for a download already in progress
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+const GROWTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+  // files served from disk
+  pub hits: u64,
+  // files downloaded
+  pub misses: u64,
+}
+
+#[derive(Educe, Clone)]
+#[educe(Default)]
+pub struct CacheOptions {
+  // how long file_by_uid waits for the remote to answer with the url
+  #[educe(Default(expression = "Duration::from_secs(5)"))]
+  pub request_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileSnapshot {
+  pub id: Vec<u8>,
+  pub path: PathBuf,
+  pub size_bytes: u64,
+  pub modified_at: SystemTime,
+}
+
+// category a download is checked against, by its magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+  Image,
+  Audio,
+  Video,
+  Document,
+}
+impl MediaKind {
+  fn accepts(self, matcher_type: infer::MatcherType) -> bool {
+    use infer::MatcherType;
+    match self {
+      MediaKind::Image => matcher_type == MatcherType::Image,
+      MediaKind::Audio => matcher_type == MatcherType::Audio,
+      MediaKind::Video => matcher_type == MatcherType::Video,
+      MediaKind::Document => matches!(
+        matcher_type,
+        MatcherType::Doc | MatcherType::Book | MatcherType::Archive
+      ),
+    }
+  }
+}
+
+// stops the watch of Cache::watch_cache_growth when dropped
+pub struct WatchHandle(JoinHandle<()>);
+impl Drop for WatchHandle {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
 #[derive(Singleton, Default)]
-pub struct Cache {}
+pub struct Cache {
+  content_addressed: LateInit<bool>,
+  options: LateInit<CacheOptions>,
+  locks: DashMap<ArcStr, Arc<Mutex<()>>>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  // replaces the DB singleton, see init_with_db
+  db: OnceCell<Arc<Db>>,
+}
 
 impl Cache {
-  pub fn init(&self) {}
+  pub fn init(&self, content_addressed: bool, options: CacheOptions) {
+    self.content_addressed.init(content_addressed);
+    self.options.init(options);
+  }
+
+  // uses db instead of the DB singleton, e.g. a db in a temp directory for
+  // tests
+  pub fn init_with_db(&self, db: Arc<Db>) {
+    if self.db.set(db).is_err() {
+      panic!("Cache db has been initialized");
+    }
+  }
+
+  fn db(&self) -> &Db {
+    self.db.get().map(Arc::as_ref).unwrap_or(&DB)
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+    }
+  }
+
+  fn is_cached(&self, path: &PathBuf) -> bool {
+    let cached = path.exists();
+    if cached {
+      self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+    cached
+  }
+
+  // path of id in the cache directory or else in the first custom directory
+  // that has it
+  pub fn file_exists_anywhere(&self, id: &[u8]) -> Option<PathBuf> {
+    let id_str: ArcStr = base64_url::encode(id).into();
+    let path = RES.path(&id_str);
+    if path.exists() {
+      return Some(path);
+    }
+    RES
+      .custom_directories()
+      .into_iter()
+      .map(|directory| directory.join(id_str.as_str()))
+      .find(|path| path.exists())
+  }
 
   pub async fn file(
     &self,
@@ -25,66 +157,590 @@ impl Cache {
     address: &ArcStr,
   ) -> Result<PathBuf> {
     match url {
-      Some(url) => self.file_by_url(id, url).await,
-      None => self.file_by_uid(id, address).await,
+      Some(url) => self.file_by_url(id, url, None).await,
+      None => self.file_by_uid(id, address, None).await,
     }
   }
 
-  pub async fn file_by_uid(&self, uid: &Vec<u8>, address: &ArcStr) -> Result<PathBuf> {
+  pub async fn file_by_uid(
+    &self,
+    uid: &Vec<u8>,
+    address: &ArcStr,
+    progress: Option<Box<dyn ProgressSink>>,
+  ) -> Result<PathBuf> {
+    self.file_by_uid_deadline(uid, address, None, progress).await
+  }
+
+  // like file_by_uid but gives up asking for the url at deadline, instead of
+  // CacheOptions::request_timeout from now
+  pub async fn file_by_uid_deadline(
+    &self,
+    uid: &[u8],
+    address: &ArcStr,
+    deadline: Option<Instant>,
+    progress: Option<Box<dyn ProgressSink>>,
+  ) -> Result<PathBuf> {
+    let deadline = deadline.unwrap_or_else(|| Instant::now() + self.options.request_timeout);
     let uid_str: ArcStr = base64_url::encode(uid).into();
     trace!("Caching file by uid {}", uid_str);
     let path = RES.path(&uid_str);
-    if path.exists() {
+    if self.is_cached(&path) {
       trace!("File exists,return the path");
       return Ok(path);
     }
     let tmp_path = RES.tmp_path(&uid_str);
     if tmp_path.exists() {
       trace!("TmpFile exists,waiting for the file downloading");
-      return Ok(RES.wait_for(&uid_str).await?);
+      let timeout = deadline.saturating_duration_since(Instant::now());
+      return Ok(RES.wait_for_with_timeout(&uid_str, timeout).await?);
     }
     trace!("TmpFile dont exist,requesting image url");
-    let packet: Event = Event::RequestImage { id: uid.clone() };
+    if let Some(progress) = &progress {
+      progress.on_bytes(0, None);
+    }
+    let packet: Event = Event::RequestImage {
+      id: uid.to_vec(),
+      platform: (*SERVER.platform).clone(),
+    };
     // fixme error handling
     let packet = Packet::from(packet.to_right())?;
-    // fixme timeout check
     let response = SERVER.request(address, packet, SERVER.new_lib_header()?);
-    let response = tokio::time::timeout(Duration::from_secs(5), response).await??;
+    let response = tokio::time::timeout_at(deadline.into(), response).await??;
     trace!("Get the image respond");
-    let r_packet = Packet::from_cbor(&response.payload)?;
-    match r_packet {
-      either::Either::Right(event) => match event {
-        Event::RespondImage { id, url } => self.file_by_url(&id, &url).await,
-        _ => panic!("Not correct response"),
-      },
-      either::Either::Left(_) => panic!("Not correct response"),
+    let raw = &response.payload;
+    let event = match Packet::from_cbor(raw) {
+      Ok(Either::Right(event)) => event,
+      Ok(Either::Left(_)) => {
+        return Err(DataError::UnexpectedResponse("message".into()).into());
+      }
+      Err(e) => {
+        debug!(
+          "Corrupted image respond {}",
+          hex::encode(&raw[..raw.len().min(64)])
+        );
+        return Err(
+          CacheError::CorruptedPacket {
+            raw_len: raw.len(),
+            detail: e.to_string(),
+          }
+          .into(),
+        );
+      }
+    };
+    let response = RespondImage::try_from(event)?;
+    self
+      .file_by_url_via(
+        &response.id,
+        &response.url,
+        NET.client(),
+        Some(MediaKind::Image),
+        progress,
+      )
+      .await
+  }
+
+  // avatars have no uid, they are cached under the sha256 of their url
+  pub async fn avatar(&self, url: &ArcStr) -> Result<PathBuf> {
+    use sha2::{Digest, Sha256};
+    let id = Sha256::digest(url.as_bytes()).to_vec();
+    self.file_by_url(&id, url, None).await
+  }
+
+  // downloads from an already known url, the remote is only asked for the url
+  // when the hint fails
+  pub async fn file_by_uid_with_url_hint(
+    &self,
+    uid: &[u8],
+    url: ArcStr,
+    address: &ArcStr,
+  ) -> Result<PathBuf> {
+    let uid = uid.to_vec();
+    match self.file_by_url(&uid, &url, None).await {
+      Ok(path) => Ok(path),
+      Err(e) => {
+        trace!("Url hint failed: {},requesting image url", e);
+        self.file_by_uid(&uid, address, None).await
+      }
+    }
+  }
+
+  // tries the renditions of an image in order, e.g. full size, thumbnail and a
+  // fallback cdn, and fails with the error of the last one
+  pub async fn file_with_fallback(
+    &self,
+    candidates: Vec<(Vec<u8>, ArcStr)>,
+    address: &ArcStr,
+  ) -> Result<PathBuf> {
+    let mut last_error = eyre!("No candidates to download");
+    for (uid, url) in candidates {
+      match self.file_by_uid_with_url_hint(&uid, url, address).await {
+        Ok(path) => return Ok(path),
+        Err(e) => {
+          trace!("Candidate {} failed: {}", base64_url::encode(&uid), e);
+          last_error = e;
+        }
+      }
+    }
+    Err(last_error)
+  }
+
+  pub async fn file_by_url(
+    &self,
+    id: &Vec<u8>,
+    url: &ArcStr,
+    progress: Option<Box<dyn ProgressSink>>,
+  ) -> Result<PathBuf> {
+    self.file_by_url_via(id, url, NET.client(), None, progress).await
+  }
+
+  // like file_by_url but fails with CacheError::ContentTypeMismatch unless the
+  // content is of the expected kind
+  pub async fn file_by_url_expecting(
+    &self,
+    id: &[u8],
+    url: &ArcStr,
+    expected: MediaKind,
+  ) -> Result<PathBuf> {
+    self
+      .file_by_url_via(id, url, NET.client(), Some(expected), None)
+      .await
+  }
+
+  // like file_by_url but downloads with client, e.g. one with another proxy
+  pub async fn file_by_url_with_client(
+    &self,
+    id: &[u8],
+    url: &ArcStr,
+    client: &reqwest::Client,
+  ) -> Result<PathBuf> {
+    self.file_by_url_via(id, url, client, None, None).await
+  }
+
+  // downloads url to dest, e.g. the media folder of a platform sdk, the file
+  // is not cached
+  pub async fn file_by_url_to_path(&self, url: &ArcStr, dest: &PathBuf) -> Result<()> {
+    let tmp_path = dest.with_extension("tmp");
+    if let Err(e) = NET.download(url, &tmp_path).await {
+      tokio::fs::remove_file(&tmp_path).await.ok();
+      return Err(e);
+    }
+    tokio::fs::rename(&tmp_path, dest).await?;
+    Ok(())
+  }
+
+  // a copy of the image of id scaled down to fit max_dim x max_dim, keeping its
+  // aspect ratio, stored as <id>.thumb_<max_dim> so it isn't taken for an id
+  pub async fn get_or_fetch_thumbnail(
+    &self,
+    id: &[u8],
+    url: &ArcStr,
+    max_dim: u32,
+  ) -> Result<PathBuf> {
+    let id_str: ArcStr = base64_url::encode(id).into();
+    let ext = format!("thumb_{}", max_dim);
+    let thumb_path = RES.path_for_ext(&id_str, &ext);
+    if self.is_cached(&thumb_path) {
+      return Ok(thumb_path);
     }
+    let owned_id = id.to_vec();
+    let url = url.clone();
+    self
+      .with_lock(id, move |_| {
+        Box::pin(async move {
+          // made by another call while waiting for the lock
+          if thumb_path.exists() {
+            return Ok(thumb_path);
+          }
+          let full_path = CACHE
+            .file_by_url_expecting(&owned_id, &url, MediaKind::Image)
+            .await?;
+          // taken after the download, which holds it itself
+          let _writing = RES.writing().await;
+          let thumb_path = RES.path_for_ext(&id_str, &ext);
+          let tmp_path = RES.tmp_path_for_ext(&id_str, &ext);
+          if let Err(e) = write_thumbnail(full_path, tmp_path.clone(), max_dim).await {
+            tokio::fs::remove_file(&tmp_path).await.ok();
+            return Err(e);
+          }
+          tokio::fs::rename(&tmp_path, &thumb_path).await?;
+          Ok(thumb_path)
+        })
+      })
+      .await
   }
 
-  pub async fn file_by_url(&self, id: &Vec<u8>, url: &ArcStr) -> Result<PathBuf> {
+  async fn file_by_url_via(
+    &self,
+    id: &[u8],
+    url: &ArcStr,
+    client: &reqwest::Client,
+    expected: Option<MediaKind>,
+    progress: Option<Box<dyn ProgressSink>>,
+  ) -> Result<PathBuf> {
     let id_str: ArcStr = base64_url::encode(id).into();
     let path = RES.path(&id_str);
-    if path.exists() {
+    if self.is_cached(&path) {
       return Ok(path);
     }
 
     let tmp_path = RES.tmp_path(&id_str);
     if tmp_path.exists() {
-      let fut = RES.wait_for(&id_str);
-      let path = tokio::time::timeout(std::time::Duration::from_secs(5), fut).await??;
-      Ok(path)
+      Ok(RES.wait_for_with_timeout(&id_str, WAIT_TIMEOUT).await?)
+    } else if *self.content_addressed {
+      // the paths again under the guard, the directory may have moved since
+      let _writing = RES.writing().await;
+      let (tmp_path, path) = (RES.tmp_path(&id_str), RES.path(&id_str));
+      self
+        .download_content_addressed(url, client, &tmp_path, &path, expected, progress)
+        .await
     } else {
-      // fixme error handling
-      NET.download(url, &tmp_path).await?;
-      tokio::fs::rename(&tmp_path, &path).await?;
+      self.misses.fetch_add(1, Ordering::Relaxed);
+      let url = url.clone();
+      let client = client.clone();
+      RES
+        .with_temp_file(&id_str, move |tmp_path| {
+          Box::pin(async move {
+            let mut tmp_file = tokio::fs::File::create(tmp_path).await?;
+            NET
+              .download_to_with(&client, &url, &mut tmp_file, progress.as_deref())
+              .await?;
+            match expected {
+              Some(expected) => check_content(tmp_path, expected).await,
+              None => Ok(()),
+            }
+          })
+        })
+        .await?;
       Ok(path)
     }
   }
 
+  async fn download_content_addressed(
+    &self,
+    url: &ArcStr,
+    client: &reqwest::Client,
+    tmp_path: &PathBuf,
+    path: &PathBuf,
+    expected: Option<MediaKind>,
+    progress: Option<Box<dyn ProgressSink>>,
+  ) -> Result<PathBuf> {
+    let result = self
+      .fetch_content_addressed(url, client, tmp_path, path, expected, progress)
+      .await;
+    // a leftover tmp file would make later calls wait for a download that
+    // never finishes
+    if result.is_err() && tmp_path.exists() {
+      if let Err(e) = tokio::fs::remove_file(tmp_path).await {
+        error!("Failed to remove {:?}: {:?}", tmp_path, e);
+      }
+    }
+    result
+  }
+
+  // content of an url still stored is revalidated with the validators of its
+  // download, if there are any, before reusing it
+  async fn fetch_content_addressed(
+    &self,
+    url: &ArcStr,
+    client: &reqwest::Client,
+    tmp_path: &PathBuf,
+    path: &PathBuf,
+    expected: Option<MediaKind>,
+    progress: Option<Box<dyn ProgressSink>>,
+  ) -> Result<PathBuf> {
+    let stored = self
+      .db()
+      .get_url_hash(url.as_bytes())
+      .map(|hash| RES.content_path(&hex::encode(&hash).into()))
+      .filter(|content_path| content_path.exists());
+    let validators = match &stored {
+      Some(content_path) => match self.db().get_url_validators(url.as_bytes()) {
+        Some(validators) => validators,
+        None => {
+          trace!("Content of {} exists,linking it", url);
+          tokio::fs::hard_link(content_path, path).await?;
+          return Ok(path.clone());
+        }
+      },
+      None => Validators::default(),
+    };
+    let fetched = {
+      let mut tmp_file = tokio::fs::File::create(tmp_path).await?;
+      NET
+        .download_to_if_modified(client, url, &mut tmp_file, &validators, progress.as_deref())
+        .await?
+    };
+    let validators = match (fetched, stored) {
+      (Some(validators), _) => validators,
+      (None, Some(content_path)) => {
+        trace!("Content of {} not modified,linking it", url);
+        tokio::fs::remove_file(tmp_path).await?;
+        tokio::fs::hard_link(&content_path, path).await?;
+        return Ok(path.clone());
+      }
+      (None, None) => {
+        return Err(eyre!("{} not modified but its content is gone", url));
+      }
+    };
+    if let Some(expected) = expected {
+      check_content(tmp_path, expected).await?;
+    }
+    self.misses.fetch_add(1, Ordering::Relaxed);
+    let hash = {
+      use sha2::{Digest, Sha256};
+      let mut hasher = Sha256::new();
+      hasher.update(tokio::fs::read(tmp_path).await?);
+      hasher.finalize()
+    };
+    let content_path = RES.content_path(&hex::encode(&hash).into());
+    if content_path.exists() {
+      // same content but from another url
+      tokio::fs::remove_file(tmp_path).await?;
+    } else {
+      tokio::fs::rename(tmp_path, &content_path).await?;
+    }
+    tokio::fs::hard_link(&content_path, path).await?;
+    self.db().put_url_hash(url.as_bytes(), hash.as_slice())?;
+    if !validators.is_empty() {
+      self.db().put_url_validators(url.as_bytes(), &validators)?;
+    }
+    Ok(path.clone())
+  }
+
+  // size of every cached file, largest first, e.g. to pick eviction candidates
+  pub async fn disk_usage_by_id(&self) -> Result<Vec<(Vec<u8>, u64)>> {
+    let mut usage = Vec::new();
+    let mut entries = tokio::fs::read_dir(RES.directory_path().as_path()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      let metadata = entry.metadata().await?;
+      if !metadata.is_file() {
+        continue;
+      }
+      let file_name = entry.file_name();
+      let file_name = file_name.to_string_lossy();
+      if file_name.ends_with(".tmp") {
+        continue;
+      }
+      match base64_url::decode(file_name.as_ref()) {
+        Ok(id) => usage.push((id, metadata.len())),
+        Err(_) => trace!("Skip foreign file {}", file_name),
+      }
+    }
+    usage.sort_by(|a, b| b.1.cmp(&a.1));
+    Ok(usage)
+  }
+
+  // metadata of every cached file, read in parallel
+  pub async fn file_snapshot(&self) -> Result<Vec<FileSnapshot>> {
+    let mut tasks = Vec::new();
+    let mut entries = tokio::fs::read_dir(RES.directory_path().as_path()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      let file_name = entry.file_name();
+      let file_name = file_name.to_string_lossy();
+      if file_name.ends_with(".tmp") {
+        continue;
+      }
+      let id = match base64_url::decode(file_name.as_ref()) {
+        Ok(id) => id,
+        Err(_) => continue,
+      };
+      let path = entry.path();
+      tasks.push(tokio::task::spawn_blocking(move || {
+        let metadata = std::fs::metadata(&path)?;
+        if !metadata.is_file() {
+          return Ok(None);
+        }
+        Ok::<_, std::io::Error>(Some(FileSnapshot {
+          id,
+          size_bytes: metadata.len(),
+          modified_at: metadata.modified()?,
+          path,
+        }))
+      }));
+    }
+    let mut snapshot = Vec::with_capacity(tasks.len());
+    for result in futures::future::join_all(tasks).await {
+      match result? {
+        Ok(Some(file)) => snapshot.push(file),
+        Ok(None) => {}
+        // removed since it was listed
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    Ok(snapshot)
+  }
+
+  // calls f with the size of the cache directory whenever a check every minute
+  // finds it above threshold_bytes
+  pub fn watch_cache_growth<F>(&self, threshold_bytes: u64, f: F) -> WatchHandle
+  where
+    F: Fn(u64) + Send + Sync + 'static,
+  {
+    self.watch_cache_growth_every(threshold_bytes, GROWTH_CHECK_INTERVAL, f)
+  }
+
+  pub fn watch_cache_growth_every<F>(
+    &self,
+    threshold_bytes: u64,
+    interval: Duration,
+    f: F,
+  ) -> WatchHandle
+  where
+    F: Fn(u64) + Send + Sync + 'static,
+  {
+    WatchHandle(tokio::spawn(async move {
+      let mut interval = tokio::time::interval(interval);
+      loop {
+        interval.tick().await;
+        match RES.directory_size().await {
+          Ok(size) if size > threshold_bytes => f(size),
+          Ok(_) => {}
+          Err(e) => debug!("Failed to measure the cache directory: {}", e),
+        }
+      }
+    }))
+  }
+
+  // downloads every image known to the db but missing on disk, e.g. after
+  // restoring a backup, returns the number of files downloaded
+  pub async fn prewarm_from_db(&self, address: &ArcStr) -> Result<usize> {
+    let mut downloaded = 0;
+    for uid in self.db().image_uids() {
+      let uid_str: ArcStr = base64_url::encode(&uid).into();
+      if RES.path(&uid_str).exists() {
+        continue;
+      }
+      match self.file_by_uid(&uid, address, None).await {
+        Ok(_) => downloaded += 1,
+        Err(e) => debug!("Failed to prewarm {}: {}", uid_str, e),
+      }
+    }
+    Ok(downloaded)
+  }
+
+  // how long ago the cached file was written, None if it is not cached
+  pub async fn file_age(&self, id: &[u8]) -> Result<Option<Duration>> {
+    let id_str: ArcStr = base64_url::encode(id).into();
+    let metadata = match tokio::fs::metadata(RES.path(&id_str)).await {
+      Ok(v) => v,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let modified = metadata.modified()?;
+    SystemTime::now()
+      .duration_since(modified)
+      .unwrap_or_default()
+      .some()
+      .ok()
+  }
+
+  // the photo url last resolved for uid, without resolving it again
+  pub async fn get_cached_url(&self, uid: &[u8]) -> Option<ArcStr> {
+    self.db().get_cached_url(uid)
+  }
+
+  // copies the cached file to dest and returns the copied bytes, a hard link
+  // holds the content while copying so it survives a concurrent removal
+  pub async fn copy_to(&self, id: &[u8], dest: &PathBuf) -> Result<u64> {
+    let id_str: ArcStr = base64_url::encode(id).into();
+    let hold_id: ArcStr = format!("{}.hold.{}", id_str, rand::random::<u32>()).into();
+    let hold_path = RES.tmp_path(&hold_id);
+    tokio::fs::hard_link(RES.path(&id_str), &hold_path).await?;
+    let copied = tokio::fs::copy(&hold_path, dest).await;
+    tokio::fs::remove_file(&hold_path).await?;
+    Ok(copied?)
+  }
+
+  // copies the cached file out of the temp directory into dest_dir, the cached
+  // file is kept
+  pub async fn move_to_permanent_storage(&self, id: &[u8], dest_dir: &PathBuf) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(dest_dir).await?;
+    let dest = dest_dir.join(base64_url::encode(id));
+    self.copy_to(id, &dest).await?;
+    Ok(dest)
+  }
+
+  // runs f with the cached path of id under a lock exclusive to id, e.g. for
+  // post-download processing
+  pub async fn with_lock<F, R>(&self, id: &[u8], f: F) -> R
+  where
+    F: FnOnce(PathBuf) -> BoxFuture<'static, R>,
+  {
+    let id_str: ArcStr = base64_url::encode(id).into();
+    let lock = self.locks.entry(id_str.clone()).or_default().clone();
+    let result = {
+      let _guard = lock.lock().await;
+      f(RES.path(&id_str)).await
+    };
+    drop(lock);
+    // forget the lock once no one else holds or waits for it
+    self
+      .locks
+      .remove_if(&id_str, |_, lock| Arc::strong_count(lock) == 1);
+    result
+  }
+
+  // writes data as the file of uid and records file_id for it, then wakes up
+  // everyone waiting for the file
+  pub async fn file_by_data_with_id(
+    &self,
+    uid: &[u8],
+    file_id: impl Into<IVec>,
+    data: &[u8],
+  ) -> Result<PathBuf> {
+    let uid_str: ArcStr = base64_url::encode(uid).into();
+    let data = data.to_vec();
+    RES
+      .with_temp_file(&uid_str, move |tmp_path| {
+        Box::pin(async move { Ok(tokio::fs::write(tmp_path, data).await?) })
+      })
+      .await?;
+    self.db().put_image_id(uid, file_id);
+    Ok(RES.path(&uid_str))
+  }
+
   pub async fn put_file(&self, id: &Vec<u8>, file: &PathBuf) -> Result<PathBuf> {
     let id_str: ArcStr = base64_url::encode(id).into();
     let path = RES.path(&id_str);
     tokio::fs::rename(&file, &path).await?;
+    RES.file_ready(&id_str, &path);
     Ok(path)
   }
 }
+
+async fn write_thumbnail(src: PathBuf, dst: PathBuf, max_dim: u32) -> Result<()> {
+  tokio::task::spawn_blocking(move || -> Result<()> {
+    let image = image::io::Reader::open(&src)?
+      .with_guessed_format()?
+      .decode()?;
+    image
+      .thumbnail(max_dim, max_dim)
+      .save_with_format(&dst, image::ImageFormat::Png)?;
+    Ok(())
+  })
+  .await?
+}
+
+// sniffs the first 512 bytes of path, a relay could serve e.g. html under an
+// image url
+async fn check_content(path: &PathBuf, expected: MediaKind) -> Result<()> {
+  use tokio::io::AsyncReadExt;
+  let mut head = Vec::with_capacity(512);
+  tokio::fs::File::open(path)
+    .await?
+    .take(512)
+    .read_to_end(&mut head)
+    .await?;
+  let actual = infer::get(&head);
+  if actual.map_or(false, |t| expected.accepts(t.matcher_type())) {
+    return Ok(());
+  }
+  Err(
+    CacheError::ContentTypeMismatch {
+      expected: format!("{:?}", expected).to_lowercase(),
+      actual: actual.map_or("unknown", |t| t.mime_type()).to_string(),
+    }
+    .into(),
+  )
+}
@@ -1,19 +1,38 @@
+use std::collections::HashMap;
+
 use arcstr::ArcStr;
 use serde::{Deserialize, Serialize};
 
+use super::message::Message;
+use crate::error::DataError;
+
+// event types Server::handle_lib_message acts on, reported in InfoResponse
+pub const SUPPORTED_EVENTS: &[&str] = &[
+  "request_image",
+  "info_request",
+  "channel_create",
+  "channel_delete",
+  "channel_rename",
+];
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 #[non_exhaustive]
 pub enum Event {
+  // platform is the one of the asking or answering adapter
   RequestImage {
     #[serde(with = "serde_bytes")]
     id: Vec<u8>,
+    #[serde(default = "crate::data::platforms::unknown")]
+    platform: ArcStr,
   },
   RespondImage {
     #[serde(with = "serde_bytes")]
     id: Vec<u8>,
     url: ArcStr,
+    #[serde(default = "crate::data::platforms::unknown")]
+    platform: ArcStr,
   },
   RequestEcho {
     // should contains group_id, group_name
@@ -23,19 +42,278 @@ pub enum Event {
     // should contains group_id, group_name
     name: ArcStr,
   },
+  // bulk delivery of messages, e.g. history replay, see Packet::from_batch
+  MessageBatch {
+    messages: Vec<Message>,
+  },
+  // message retraction, adapters should delete or strike through the
+  // referenced message if their platform supports it
+  Revoke {
+    #[serde(with = "serde_bytes")]
+    message_id: Vec<u8>,
+    channel: ArcStr,
+  },
+  // adapters without native polls should render the question and the options
+  // as a numbered list in a text message
+  Poll {
+    question: ArcStr,
+    options: Vec<ArcStr>,
+    #[serde(with = "serde_bytes")]
+    poll_id: Vec<u8>,
+  },
+  PollVote {
+    #[serde(with = "serde_bytes")]
+    poll_id: Vec<u8>,
+    voter: ArcStr,
+    option_index: u8,
+  },
+  // votes per option, in the order of Poll::options
+  PollResult {
+    #[serde(with = "serde_bytes")]
+    poll_id: Vec<u8>,
+    results: Vec<u32>,
+  },
+  // relay topology, registers/deregisters channel in the routing table
+  ChannelCreate {
+    channel: ArcStr,
+    metadata: HashMap<ArcStr, ArcStr>,
+  },
+  ChannelDelete {
+    channel: ArcStr,
+  },
+  // adapters should update the displayed name of the channel, and may notify
+  // its members
+  ChannelRename {
+    channel: ArcStr,
+    new_name: ArcStr,
+  },
+  // call notifications, ended: false when the call started, ended: true with
+  // the duration when it ended, the media itself is never bridged
+  AudioCall {
+    caller: ArcStr,
+    channel: ArcStr,
+    duration_secs: Option<u32>,
+    ended: bool,
+  },
+  VideoCall {
+    caller: ArcStr,
+    channel: ArcStr,
+    duration_secs: Option<u32>,
+    ended: bool,
+  },
+  // last reply to a request answered with several packets, see
+  // Server::request_stream
+  EndOfStream {},
+  // asks the relay on the address which events it understands
+  InfoRequest {},
+  InfoResponse {
+    version: ArcStr,
+    supported_events: Vec<ArcStr>,
+    max_packet_bytes: u64,
+  },
+  // adapters should use the announcement feature of their platform, e.g.
+  // discord announcements or telegram channel posts, and fall back to a plain
+  // message otherwise
+  Announcement {
+    text: ArcStr,
+    channel: ArcStr,
+    pinned: bool,
+  },
+}
+impl Event {
+  // whether the remote answers this event with a response
+  pub fn is_request(&self) -> bool {
+    matches!(
+      self,
+      Event::RequestImage { .. } | Event::RequestEcho { .. } | Event::InfoRequest {}
+    )
+  }
+
+  // the serialised type tag, e.g. "request_image"
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Event::RequestImage { .. } => "request_image",
+      Event::RespondImage { .. } => "respond_image",
+      Event::RequestEcho { .. } => "request_echo",
+      Event::RespondEcho { .. } => "respond_echo",
+      Event::MessageBatch { .. } => "message_batch",
+      Event::Revoke { .. } => "revoke",
+      Event::Poll { .. } => "poll",
+      Event::PollVote { .. } => "poll_vote",
+      Event::PollResult { .. } => "poll_result",
+      Event::ChannelCreate { .. } => "channel_create",
+      Event::ChannelDelete { .. } => "channel_delete",
+      Event::ChannelRename { .. } => "channel_rename",
+      Event::AudioCall { .. } => "audio_call",
+      Event::VideoCall { .. } => "video_call",
+      Event::EndOfStream {} => "end_of_stream",
+      Event::InfoRequest {} => "info_request",
+      Event::InfoResponse { .. } => "info_response",
+      Event::Announcement { .. } => "announcement",
+    }
+  }
+
+  // the channel the event is about, if any
+  pub fn channel(&self) -> Option<&ArcStr> {
+    match self {
+      Event::Revoke { channel, .. }
+      | Event::ChannelCreate { channel, .. }
+      | Event::ChannelDelete { channel }
+      | Event::ChannelRename { channel, .. }
+      | Event::AudioCall { channel, .. }
+      | Event::VideoCall { channel, .. }
+      | Event::Announcement { channel, .. } => Some(channel),
+      _ => None,
+    }
+  }
+
+  pub fn revoke(message_id: Vec<u8>, channel: impl Into<ArcStr>) -> Event {
+    Event::Revoke {
+      message_id,
+      channel: channel.into(),
+    }
+  }
+
+  pub fn announcement(text: impl Into<ArcStr>, channel: impl Into<ArcStr>) -> Event {
+    Event::Announcement {
+      text: text.into(),
+      channel: channel.into(),
+      pinned: false,
+    }
+  }
+
+  pub fn pinned_announcement(text: impl Into<ArcStr>, channel: impl Into<ArcStr>) -> Event {
+    Event::Announcement {
+      text: text.into(),
+      channel: channel.into(),
+      pinned: true,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct RespondImage {
+  pub id: Vec<u8>,
+  pub url: ArcStr,
+  pub platform: ArcStr,
+}
+impl TryFrom<Event> for RespondImage {
+  type Error = DataError;
+
+  fn try_from(event: Event) -> Result<Self, Self::Error> {
+    match event {
+      Event::RespondImage { id, url, platform } => Ok(Self { id, url, platform }),
+      other => Err(DataError::UnexpectedResponse(format!("{:?}", other))),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct RespondEcho {
+  pub name: ArcStr,
+}
+impl TryFrom<Event> for RespondEcho {
+  type Error = DataError;
+
+  fn try_from(event: Event) -> Result<Self, Self::Error> {
+    match event {
+      Event::RespondEcho { name } => Ok(Self { name }),
+      other => Err(DataError::UnexpectedResponse(format!("{:?}", other))),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayInfo {
+  pub version: ArcStr,
+  pub supported_events: Vec<ArcStr>,
+  pub max_packet_bytes: u64,
+}
+impl TryFrom<Event> for RelayInfo {
+  type Error = DataError;
+
+  fn try_from(event: Event) -> Result<Self, Self::Error> {
+    match event {
+      Event::InfoResponse {
+        version,
+        supported_events,
+        max_packet_bytes,
+      } => Ok(Self {
+        version,
+        supported_events,
+        max_packet_bytes,
+      }),
+      other => Err(DataError::UnexpectedResponse(format!("{:?}", other))),
+    }
+  }
 }
 
 #[cfg(test)]
 mod test {
-  use crate::data::events::*;
+  use crate::data::{events::*, platforms};
   #[test]
   fn test() {
     let event = Event::RequestImage {
       id: "dd".as_bytes().to_owned(),
+      platform: platforms::PLATFORM_QQ,
     };
     let strw = serde_cbor::to_vec(&event).unwrap();
     println!("{} \n check in http://cbor.me/", hex::encode(&strw));
     let a = serde_cbor::from_slice::<Event>(&strw).is_ok();
     assert!(a);
   }
+
+  #[test]
+  fn kind() {
+    let event = Event::ChannelDelete {
+      channel: "channel".into(),
+    };
+    let value = serde_cbor::value::to_value(&event).unwrap();
+    let tag = match value {
+      serde_cbor::Value::Map(map) => map[&serde_cbor::Value::Text("type".into())].clone(),
+      _ => panic!("not a map"),
+    };
+    assert_eq!(tag, serde_cbor::Value::Text(event.kind().into()));
+    assert!(SUPPORTED_EVENTS.contains(&event.kind()));
+    // replies are sent by the lib, never acted on
+    assert!(!SUPPORTED_EVENTS.contains(&"respond_image"));
+  }
+
+  #[test]
+  fn announcement() {
+    let event = Event::pinned_announcement("text", "channel");
+    let bytes = serde_cbor::to_vec(&event).unwrap();
+    match serde_cbor::from_slice::<Event>(&bytes).unwrap() {
+      Event::Announcement {
+        text,
+        channel,
+        pinned,
+      } => {
+        assert_eq!(text, "text");
+        assert_eq!(channel, "channel");
+        assert!(pinned);
+      }
+      other => panic!("unexpected event {:?}", other),
+    }
+  }
+
+  #[test]
+  fn respond_image_platform() {
+    let event = Event::RespondImage {
+      id: Vec::from("id"),
+      url: "https://example.com/image".into(),
+      platform: platforms::PLATFORM_TELEGRAM,
+    };
+    let strw = serde_cbor::to_vec(&event).unwrap();
+    let response = RespondImage::try_from(serde_cbor::from_slice::<Event>(&strw).unwrap()).unwrap();
+    assert_eq!(response.platform, platforms::PLATFORM_TELEGRAM);
+    // packets of older versions don't have the field
+    let mut value = serde_cbor::value::to_value(&event).unwrap();
+    if let serde_cbor::Value::Map(map) = &mut value {
+      map.remove(&serde_cbor::Value::Text("platform".into()));
+    }
+    let strw = serde_cbor::to_vec(&value).unwrap();
+    let response = RespondImage::try_from(serde_cbor::from_slice::<Event>(&strw).unwrap()).unwrap();
+    assert_eq!(response.platform, platforms::PLATFORM_UNKNOWN);
+  }
 }
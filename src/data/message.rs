@@ -3,7 +3,7 @@ use std::convert::TryInto;
 use arcstr::ArcStr;
 use serde::{Deserialize, Serialize};
 
-use crate::{OptionExt, ResultExt};
+use crate::{error::DataError, OptionExt, ResultExt};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Profile {
@@ -13,6 +13,19 @@ pub struct Profile {
   pub nick: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Location {
+  pub lat: f64,
+  pub lon: f64,
+  pub label: Option<ArcStr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReactionSummary {
+  pub emoji: ArcStr,
+  pub count: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct Message {
@@ -22,17 +35,128 @@ pub struct Message {
   #[serde(with = "serde_bytes")]
   pub reply: Option<Vec<u8>>,
   pub chain: Vec<MessageType>,
+  // routing relies on it, see Message::validate
+  #[serde(default)]
+  pub channel: ArcStr,
+  // source platform for attribution, see data::platforms
+  #[serde(default = "crate::data::platforms::unknown")]
+  pub platform: ArcStr,
+  // random value to drop duplicates delivered over several relay hops, only
+  // meaningful within the channel, see data::nonce::NonceTracker
+  #[serde(default)]
+  pub nonce: Option<u64>,
+  // display name of the sender at send time, renderers should prefer it over
+  // looking up the current name
+  #[serde(default)]
+  pub sender_display_name: Option<ArcStr>,
+  // adapters should set it when their platform marks the message as forwarded,
+  // e.g. forward_from on telegram
+  #[serde(default)]
+  pub is_forwarded: bool,
+  // map pin accompanying the text, unlike a standalone MessageType::Location
+  #[serde(default)]
+  pub geo: Option<Location>,
+  // reaction counts known at send time, adapters without reactions should
+  // ignore them
+  #[serde(default)]
+  pub reactions: Vec<ReactionSummary>,
+  // prior text versions, oldest first
+  #[serde(default)]
+  pub edit_history: Vec<ArcStr>,
+  // BCP-47 code of the source language if the platform tells it, translating
+  // adapters should translate from it
+  #[serde(default)]
+  pub language: Option<ArcStr>,
+  // only set with a stable and publicly accessible url, see Cache::avatar
+  #[serde(default)]
+  pub sender_avatar_url: Option<ArcStr>,
+  // hidden behind a spoiler toggle, e.g. spoiler tags on discord or the spoiler
+  // entity on telegram, adapters without spoilers deliver the content as is
+  #[serde(default)]
+  pub spoiler: bool,
+  // thread the message belongs to
+  #[serde(default, with = "serde_bytes")]
+  pub thread_id: Option<Vec<u8>>,
+  // set by the adapter that started the thread with this message, adapters
+  // supporting threads should start a new thread for it
+  #[serde(default)]
+  pub thread_root: bool,
+  // the sender is hidden, profile may be a placeholder with an empty id,
+  // adapters should render the sender as "Anonymous"
+  #[serde(default)]
+  pub is_anonymous: bool,
+  // users who confirmed reading the message, adapters with read receipts send
+  // the message again as an edit when it grows
+  #[serde(default)]
+  pub read_by: Vec<ArcStr>,
+  // content warning shown before the message, adapters should use the closest
+  // equivalent of their platform, e.g. spoiler tags on discord
+  #[serde(default)]
+  pub content_warning: Option<ArcStr>,
+  // attachments of the original message, e.g. to preview "3 images" before
+  // downloading them, set even when the chain doesn't carry them all
+  #[serde(default)]
+  pub attachments_count: u8,
+  // link to the original message, e.g. a discord message link, adapters may
+  // append it as a "view original" footer
+  #[serde(default)]
+  pub source_message_url: Option<ArcStr>,
 }
 impl Message {
-  pub fn new(profile: Profile, id: i32, chain: Vec<MessageType>) -> Self {
+  pub fn new(
+    profile: Profile,
+    id: i32,
+    chain: Vec<MessageType>,
+    channel: impl Into<ArcStr>,
+    platform: impl Into<ArcStr>,
+  ) -> Self {
     Message {
       profile,
       id: id.to_be_bytes().to_vec(),
       reply: None,
       chain,
+      channel: channel.into(),
+      platform: platform.into(),
+      nonce: Some(rand::random()),
+      sender_display_name: None,
+      is_forwarded: false,
+      geo: None,
+      reactions: Vec::new(),
+      edit_history: Vec::new(),
+      language: None,
+      sender_avatar_url: None,
+      spoiler: false,
+      thread_id: None,
+      thread_root: false,
+      is_anonymous: false,
+      read_by: Vec::new(),
+      content_warning: None,
+      attachments_count: 0,
+      source_message_url: None,
     }
   }
 
+  pub fn validate(&self) -> Result<(), DataError> {
+    if self.id.is_empty() {
+      return Err(DataError::InvalidPacket("empty message id".into()));
+    }
+    if self.profile.id.is_empty() && !self.is_anonymous {
+      return Err(DataError::InvalidPacket("empty sender id".into()));
+    }
+    if self.channel.is_empty() {
+      return Err(DataError::MissingChannel);
+    }
+    if self.platform.is_empty() {
+      return Err(DataError::InvalidPacket("empty platform".into()));
+    }
+    Ok(())
+  }
+
+  // records text as the version before the current one
+  pub fn push_edit(&mut self, text: impl Into<ArcStr>) {
+    self.edit_history.push(text.into());
+  }
+
   pub fn id_i64(&self) -> Option<i64> {
     i64::from_be_bytes(self.id.clone().try_into().ignore()?).some()
   }
@@ -52,12 +176,26 @@ pub enum MessageType {
     #[serde(with = "serde_bytes")]
     id: Vec<u8>,
     url: Option<ArcStr>,
+    // like Message::spoiler, for this image only
+    #[serde(default)]
+    spoiler: bool,
+  },
+  // geo pin, adapters for platforms without native location support
+  // should render it as a map url in plain text, e.g.
+  // https://www.openstreetmap.org/?mlat={latitude}&mlon={longitude}
+  Location {
+    latitude: f64,
+    longitude: f64,
+    label: Option<ArcStr>,
   },
 }
 
 #[cfg(test)]
 mod test {
-  use crate::data::message::{Message, MessageType, Profile};
+  use crate::data::{
+    message::{Message, MessageType, Profile},
+    platforms::{PLATFORM_DISCORD, PLATFORM_UNKNOWN},
+  };
   #[test]
   fn test() {
     let message = Message {
@@ -77,13 +215,111 @@ mod test {
         MessageType::Image {
           id: Vec::from("id"),
           url: None,
+          spoiler: false,
         },
       ],
       reply: None,
+      channel: "channel".into(),
+      platform: PLATFORM_DISCORD,
+      nonce: None,
+      sender_display_name: None,
+      is_forwarded: false,
+      geo: None,
+      reactions: Vec::new(),
+      edit_history: Vec::new(),
+      language: None,
+      sender_avatar_url: None,
+      spoiler: false,
+      thread_id: None,
+      thread_root: false,
+      is_anonymous: false,
+      read_by: Vec::new(),
+      content_warning: None,
+      attachments_count: 0,
+      source_message_url: None,
     };
     let strw = serde_cbor::to_vec(&message).unwrap();
     println!("{} \n check in http://cbor.me/", hex::encode(&strw));
     let a = serde_cbor::from_slice::<Message>(&strw).is_ok();
     assert!(a);
   }
+
+  #[test]
+  fn anonymous() {
+    let profile = Profile {
+      id: Vec::new(),
+      username: None,
+      nick: None,
+    };
+    let mut message = Message::new(profile, 1, vec![], "channel", PLATFORM_DISCORD);
+    assert!(message.validate().is_err());
+    message.is_anonymous = true;
+    assert!(message.validate().is_ok());
+  }
+
+  #[test]
+  fn content_warning() {
+    let profile = Profile {
+      id: Vec::from("user"),
+      username: None,
+      nick: None,
+    };
+    let mut message = Message::new(profile, 1, vec![], "channel", PLATFORM_DISCORD);
+    message.content_warning = Some("spoilers".into());
+    let strw = serde_cbor::to_vec(&message).unwrap();
+    let decoded = serde_cbor::from_slice::<Message>(&strw).unwrap();
+    assert_eq!(decoded.content_warning.as_deref(), Some("spoilers"));
+    // packets of older versions don't have the field
+    let mut value = serde_cbor::value::to_value(&message).unwrap();
+    if let serde_cbor::Value::Map(map) = &mut value {
+      map.remove(&serde_cbor::Value::Text("content_warning".into()));
+    }
+    let strw = serde_cbor::to_vec(&value).unwrap();
+    let decoded = serde_cbor::from_slice::<Message>(&strw).unwrap();
+    assert_eq!(decoded.content_warning, None);
+  }
+
+  #[test]
+  fn missing_platform() {
+    let profile = Profile {
+      id: Vec::from("user"),
+      username: None,
+      nick: None,
+    };
+    let message = Message::new(profile, 1, vec![], "channel", PLATFORM_DISCORD);
+    // packets of older versions don't have the field
+    let mut value = serde_cbor::value::to_value(&message).unwrap();
+    if let serde_cbor::Value::Map(map) = &mut value {
+      map.remove(&serde_cbor::Value::Text("platform".into()));
+    }
+    let strw = serde_cbor::to_vec(&value).unwrap();
+    let decoded = serde_cbor::from_slice::<Message>(&strw).unwrap();
+    assert_eq!(decoded.platform, PLATFORM_UNKNOWN);
+    assert!(decoded.validate().is_ok());
+    let mut message = decoded;
+    message.platform = "".into();
+    assert!(message.validate().is_err());
+  }
+
+  #[test]
+  fn location() {
+    let location = MessageType::Location {
+      latitude: 35.6586,
+      longitude: 139.7454,
+      label: Some("Tokyo Tower".into()),
+    };
+    let strw = serde_cbor::to_vec(&location).unwrap();
+    match serde_cbor::from_slice::<MessageType>(&strw).unwrap() {
+      MessageType::Location {
+        latitude,
+        longitude,
+        label,
+      } => {
+        assert_eq!(latitude, 35.6586);
+        assert_eq!(longitude, 139.7454);
+        assert_eq!(label.as_deref(), Some("Tokyo Tower"));
+      }
+      _ => panic!("not a location"),
+    }
+  }
 }
@@ -1,15 +1,35 @@
 pub mod events;
 pub mod message;
+pub mod nonce;
+pub mod platforms;
 
-use std::convert::TryFrom;
+use std::{
+  convert::TryFrom,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use aes_gcm::aead::Aead;
 use color_eyre::{eyre, eyre::Result};
 use either::Either;
+use hmac::{Hmac, Mac};
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use sha2::Sha256;
+use tracing::error;
+use uuid::Uuid;
 
 use self::{events::Event, message::Message};
-use crate::{cipher::CIPHER, EitherExt, OkExt};
+use crate::{cipher::CIPHER, error::DataError, EitherExt, OkExt};
+
+// derives a stable id from the content, laid out as an uuid v4
+pub fn new_id_from_bytes(data: &[u8]) -> Uuid {
+  use sha2::Digest;
+  let hash = Sha256::digest(data);
+  let mut bytes = [0u8; 16];
+  bytes.copy_from_slice(&hash[..16]);
+  uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Packet {
@@ -20,6 +40,22 @@ pub struct Packet {
   #[serde(with = "serde_bytes")]
   pub encrypt: Vec<u8>,
   pub version: String,
+  // unix milliseconds of the creation, 0 for packets of older versions
+  #[serde(default)]
+  pub timestamp: u64,
+  // seconds after timestamp the packet is no longer worth delivering
+  #[serde(default)]
+  pub ttl: Option<u32>,
+  // HMAC-SHA256 of the packet, empty when not signed, see Packet::sign
+  #[serde(default, with = "serde_bytes")]
+  pub signature: Vec<u8>,
+  // the plaintext is zstd compressed before encryption, see
+  // Packet::with_compression
+  #[serde(default)]
+  pub compressed: bool,
+  // see Packet::as_event
+  #[serde(skip)]
+  decoded: OnceCell<Event>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,6 +72,19 @@ impl Packet {
     Self::encrypt_from(data)
   }
 
+  // splits messages into MessageBatch packets holding at most `limit` messages
+  pub fn from_batch(messages: Vec<Message>, limit: usize) -> Result<Vec<Self>> {
+    let limit = limit.max(1);
+    let mut packets = Vec::new();
+    let mut messages = messages.into_iter().peekable();
+    while messages.peek().is_some() {
+      let chunk: Vec<Message> = messages.by_ref().take(limit).collect();
+      let event = Event::MessageBatch { messages: chunk };
+      packets.push(Self::encrypt_from(event.to_right())?);
+    }
+    Ok(packets)
+  }
+
   fn encrypt_from(data: Either<message::Message, events::Event>) -> Result<Self> {
     let bytes_nonce = CIPHER.new_nonce();
     let nonce = aes_gcm::Nonce::from_slice(&bytes_nonce);
@@ -57,27 +106,286 @@ impl Packet {
       content: ciphertext,
       encrypt: bytes_nonce.into(),
       version: "v1".into(),
+      timestamp: unix_millis(),
+      ttl: None,
+      signature: Vec::new(),
+      compressed: false,
+      decoded: OnceCell::new(),
     }
     .ok()
   }
 
+  pub fn age(&self) -> Duration {
+    Duration::from_millis(unix_millis().saturating_sub(self.timestamp))
+  }
+
+  // compresses or decompresses the content, which is encrypted again with a new
+  // nonce, so call it before Packet::sign
+  pub fn with_compression(mut self, enable: bool) -> Self {
+    if self.compressed == enable {
+      return self;
+    }
+    match self.recompress(enable) {
+      Ok((content, nonce)) => {
+        self.content = content;
+        self.encrypt = nonce;
+        self.compressed = enable;
+        self.decoded = OnceCell::new();
+      }
+      Err(e) => error!("Failed to change compression of packet: {}", e),
+    }
+    self
+  }
+
+  fn recompress(&self, enable: bool) -> Result<(Vec<u8>, Vec<u8>)> {
+    let plaintext = self.plaintext()?;
+    let plaintext = if enable {
+      zstd::encode_all(plaintext.as_slice(), 0)?
+    } else {
+      zstd::decode_all(plaintext.as_slice())?
+    };
+    let bytes_nonce = CIPHER.new_nonce();
+    let nonce = aes_gcm::Nonce::from_slice(&bytes_nonce);
+    let ciphertext = CIPHER.encrypt(nonce, plaintext.as_ref())?;
+    Ok((ciphertext, bytes_nonce.into()))
+  }
+
+  // decrypted content, still compressed when the packet is
+  fn plaintext(&self) -> Result<Vec<u8>> {
+    let nonce = aes_gcm::Nonce::from_slice(&self.encrypt);
+    Ok(CIPHER.decrypt(nonce, self.content.as_ref())?)
+  }
+
+  pub fn with_ttl(mut self, secs: u32) -> Self {
+    self.ttl = Some(secs);
+    self
+  }
+
+  // packets of older versions carry no timestamp and never expire
+  pub fn is_expired(&self) -> bool {
+    match self.ttl {
+      Some(ttl) if self.timestamp != 0 => self.age() > Duration::from_secs(ttl as u64),
+      _ => false,
+    }
+  }
+
+  pub fn validate(&self) -> Result<(), DataError> {
+    match self.r#type.as_str() {
+      "message" | "event" => {}
+      ty => return Err(DataError::InvalidPacket(format!("unknown type {}", ty))),
+    }
+    if self.content.is_empty() {
+      return Err(DataError::InvalidPacket("empty content".into()));
+    }
+    // Nonce::from_slice panics on any other length
+    if self.encrypt.len() != 12 {
+      return Err(DataError::InvalidPacket(format!(
+        "nonce of {} bytes",
+        self.encrypt.len()
+      )));
+    }
+    Ok(())
+  }
+
   pub fn from_cbor(data: &[u8]) -> Result<Either<message::Message, Event>> {
     let packet: Packet = serde_cbor::from_slice(data)?;
-    let nonce = aes_gcm::Nonce::from_slice(&packet.encrypt);
-    let plaintext = CIPHER.decrypt(nonce, packet.content.as_ref())?;
-    match packet.r#type.as_str() {
-      "message" => serde_cbor::from_slice::<Message>(&plaintext)?
-        .to_left()
-        .ok(),
+    packet.decode()
+  }
+
+  // decrypts the content
+  pub fn decode(&self) -> Result<Either<message::Message, Event>> {
+    self.validate()?;
+    let mut plaintext = self.plaintext()?;
+    if self.compressed {
+      plaintext = zstd::decode_all(plaintext.as_slice())?;
+    }
+    match self.r#type.as_str() {
+      "message" => {
+        let message = serde_cbor::from_slice::<Message>(&plaintext)?;
+        message.validate()?;
+        message.to_left().ok()
+      }
       "event" => serde_cbor::from_slice::<Event>(&plaintext)?.to_right().ok(),
       &_ => unreachable!(),
     }
   }
 
+  pub fn into_event(self) -> Result<Event, DataError> {
+    match self.decoded.into_inner() {
+      Some(event) => Ok(event),
+      None => decode_event(&self),
+    }
+  }
+
+  // decodes the event once and keeps it for later calls
+  pub fn as_event(&self) -> Result<&Event, DataError> {
+    self.decoded.get_or_try_init(|| decode_event(self))
+  }
+
+  // serialised length, counted without allocating the serialisation
+  pub fn size_bytes(&self) -> usize {
+    let mut writer = CountingWriter(0);
+    serde_cbor::to_writer(&mut writer, self).expect("Packet is always serializable");
+    writer.0
+  }
+
   pub fn to_cbor(self) -> Result<Vec<u8>> {
     Ok(serde_cbor::to_vec(&self)?)
   }
+
+  // for relays speaking json, bytes become arrays of numbers
+  pub fn to_json(&self) -> Result<String, DataError> {
+    serde_json::to_string(self).map_err(|e| DataError::Malformed(e.to_string()))
+  }
+
+  pub fn from_json(s: &str) -> Result<Self, DataError> {
+    serde_json::from_str(s).map_err(|e| DataError::Malformed(e.to_string()))
+  }
+
+  pub fn sign(&mut self, key: &[u8]) {
+    self.signature = self.mac(key).finalize().into_bytes().to_vec();
+  }
+
+  pub fn verify_signature(&self, key: &[u8]) -> bool {
+    self.check_signature(key).is_ok()
+  }
+
+  pub fn check_signature(&self, key: &[u8]) -> Result<(), DataError> {
+    if self.signature.is_empty() {
+      return Err(DataError::SignatureMissing);
+    }
+    self
+      .mac(key)
+      .verify_slice(&self.signature)
+      .map_err(|_| DataError::SignatureInvalid)
+  }
+
+  // over the cbor value tree with an empty signature, its map keys are sorted
+  // so the encoding doesn't depend on the field order
+  fn mac(&self, key: &[u8]) -> Hmac<Sha256> {
+    let mut value = serde_cbor::value::to_value(self).expect("Packet is always serializable");
+    if let Value::Map(map) = &mut value {
+      map.insert(Value::Text("signature".into()), Value::Bytes(Vec::new()));
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&serde_cbor::to_vec(&value).expect("Packet is always serializable"));
+    mac
+  }
+
+  // fields differing from other, e.g. to compare an outgoing packet with the
+  // one that came back from a relay
+  pub fn diff(&self, other: &Packet) -> Vec<PacketDiff> {
+    let before = serde_cbor::value::to_value(self).expect("Packet is always serializable");
+    let after = serde_cbor::value::to_value(other).expect("Packet is always serializable");
+    let mut diffs = Vec::new();
+    diff_value(String::new(), &before, &after, &mut diffs);
+    diffs
+  }
 }
+
+// fields are paths into the cbor tree, e.g. "version"
+#[derive(Debug, PartialEq)]
+pub enum PacketDiff {
+  FieldChanged {
+    field: String,
+    before: String,
+    after: String,
+  },
+  FieldAdded {
+    field: String,
+    value: String,
+  },
+  FieldRemoved {
+    field: String,
+    value: String,
+  },
+}
+
+fn diff_value(path: String, before: &Value, after: &Value, diffs: &mut Vec<PacketDiff>) {
+  let field = |name: String| {
+    if path.is_empty() {
+      name
+    } else {
+      format!("{}.{}", path, name)
+    }
+  };
+  let key_name = |key: &Value| match key {
+    Value::Text(name) => name.clone(),
+    key => format!("{:?}", key),
+  };
+  match (before, after) {
+    (Value::Map(before), Value::Map(after)) => {
+      for (key, value) in before {
+        match after.get(key) {
+          Some(other) => diff_value(field(key_name(key)), value, other, diffs),
+          None => diffs.push(PacketDiff::FieldRemoved {
+            field: field(key_name(key)),
+            value: format!("{:?}", value),
+          }),
+        }
+      }
+      for (key, value) in after {
+        if !before.contains_key(key) {
+          diffs.push(PacketDiff::FieldAdded {
+            field: field(key_name(key)),
+            value: format!("{:?}", value),
+          });
+        }
+      }
+    }
+    (Value::Array(before), Value::Array(after)) => {
+      for i in 0..before.len().max(after.len()) {
+        let field = format!("{}[{}]", path, i);
+        match (before.get(i), after.get(i)) {
+          (Some(value), Some(other)) => diff_value(field, value, other, diffs),
+          (Some(value), None) => diffs.push(PacketDiff::FieldRemoved {
+            field,
+            value: format!("{:?}", value),
+          }),
+          (None, Some(value)) => diffs.push(PacketDiff::FieldAdded {
+            field,
+            value: format!("{:?}", value),
+          }),
+          (None, None) => unreachable!(),
+        }
+      }
+    }
+    (before, after) if before != after => diffs.push(PacketDiff::FieldChanged {
+      field: path,
+      before: format!("{:?}", before),
+      after: format!("{:?}", after),
+    }),
+    _ => {}
+  }
+}
+
+fn decode_event(packet: &Packet) -> Result<Event, DataError> {
+  match packet.decode() {
+    Ok(Either::Right(event)) => Ok(event),
+    Ok(Either::Left(_)) => Err(DataError::NotAnEvent),
+    Err(e) => Err(DataError::Malformed(e.to_string())),
+  }
+}
+
+struct CountingWriter(usize);
+impl std::io::Write for CountingWriter {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0 += buf.len();
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+fn unix_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or_default()
+}
+
 impl TryFrom<Either<message::Message, events::Event>> for Packet {
   type Error = eyre::Error;
 
@@ -87,17 +395,29 @@ impl TryFrom<Either<message::Message, events::Event>> for Packet {
 }
 #[cfg(test)]
 mod test {
+  use std::sync::Once;
+
   use crate::{
     cipher::CIPHER,
     data::{
+      events::Event,
       message::{self, Message},
-      Packet,
+      new_id_from_bytes,
+      platforms::PLATFORM_TELEGRAM,
+      Packet, PacketDiff,
     },
+    error::DataError,
     EitherExt,
   };
+
+  fn init_cipher() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| CIPHER.init(&"this is key".to_string().into()));
+  }
+
   #[test]
   fn test() {
-    CIPHER.init(&"this is key".to_string().into());
+    init_cipher();
     let message = Message {
       profile: message::Profile {
         id: 1223232i64.to_be_bytes().to_vec(),
@@ -114,6 +434,24 @@ mod test {
           content: "this is text".to_string(),
         },
       ],
+      channel: "channel".into(),
+      platform: PLATFORM_TELEGRAM,
+      nonce: None,
+      sender_display_name: None,
+      is_forwarded: false,
+      geo: None,
+      reactions: Vec::new(),
+      edit_history: Vec::new(),
+      language: None,
+      sender_avatar_url: None,
+      spoiler: false,
+      thread_id: None,
+      thread_root: false,
+      is_anonymous: false,
+      read_by: Vec::new(),
+      content_warning: None,
+      attachments_count: 0,
+      source_message_url: None,
     };
     let packet = Packet::encrypt_from(message.to_left()).unwrap();
     let cbor_packet = serde_cbor::to_vec(&packet).unwrap();
@@ -121,4 +459,174 @@ mod test {
     let packet2 = Packet::from_cbor(&cbor_packet);
     assert!(packet2.is_ok());
   }
+
+  #[test]
+  fn batch() {
+    init_cipher();
+    let messages = (0..250)
+      .map(|i| {
+        let profile = message::Profile {
+          id: Vec::from("user"),
+          username: None,
+          nick: None,
+        };
+        Message::new(profile, i, vec![], "channel", PLATFORM_TELEGRAM)
+      })
+      .collect();
+    let packets = Packet::from_batch(messages, 100).unwrap();
+    assert_eq!(packets.len(), 3);
+    let last = Packet::from_cbor(&serde_cbor::to_vec(&packets[2]).unwrap()).unwrap();
+    match last.expect_right("not an event") {
+      Event::MessageBatch { messages } => assert_eq!(messages.len(), 50),
+      _ => panic!("not a batch"),
+    }
+  }
+
+  #[test]
+  fn validate() {
+    init_cipher();
+    let event = Event::RequestEcho {
+      name: "echo".into(),
+    };
+    let mut packet = Packet::from(event.to_right()).unwrap();
+    assert!(packet.validate().is_ok());
+    assert_eq!(packet.size_bytes(), serde_cbor::to_vec(&packet).unwrap().len());
+    packet.encrypt.truncate(4);
+    assert!(packet.validate().is_err());
+    let cbor_packet = serde_cbor::to_vec(&packet).unwrap();
+    assert!(Packet::from_cbor(&cbor_packet).is_err());
+  }
+
+  #[test]
+  fn ttl() {
+    init_cipher();
+    let event = Event::RequestEcho {
+      name: "echo".into(),
+    };
+    let mut packet = Packet::from(event.to_right()).unwrap().with_ttl(60);
+    assert!(!packet.is_expired());
+    packet.timestamp -= 61 * 1000;
+    assert!(packet.is_expired());
+    packet.timestamp = 0;
+    assert!(!packet.is_expired());
+  }
+
+  #[test]
+  fn diff() {
+    init_cipher();
+    let event = Event::RequestEcho {
+      name: "echo".into(),
+    };
+    let packet = Packet::from(event.to_right()).unwrap();
+    let mut other: Packet = serde_cbor::from_slice(&serde_cbor::to_vec(&packet).unwrap()).unwrap();
+    assert!(packet.diff(&other).is_empty());
+    other.version = "v2".into();
+    assert_eq!(
+      packet.diff(&other),
+      vec![PacketDiff::FieldChanged {
+        field: "version".into(),
+        before: "Text(\"v1\")".into(),
+        after: "Text(\"v2\")".into(),
+      }]
+    );
+  }
+
+  #[test]
+  fn signature() {
+    init_cipher();
+    let event = Event::RequestEcho {
+      name: "echo".into(),
+    };
+    let mut packet = Packet::from(event.to_right()).unwrap();
+    assert!(matches!(
+      packet.check_signature(b"key"),
+      Err(DataError::SignatureMissing)
+    ));
+    packet.sign(b"key");
+    assert!(packet.verify_signature(b"key"));
+    assert!(!packet.verify_signature(b"another key"));
+    packet.version = "v2".into();
+    assert!(matches!(
+      packet.check_signature(b"key"),
+      Err(DataError::SignatureInvalid)
+    ));
+  }
+
+  #[test]
+  fn as_event() {
+    init_cipher();
+    let event = Event::RequestEcho {
+      name: "echo".into(),
+    };
+    let packet = Packet::from(event.to_right()).unwrap();
+    assert!(matches!(packet.as_event(), Ok(Event::RequestEcho { .. })));
+    assert!(matches!(packet.into_event(), Ok(Event::RequestEcho { .. })));
+    let profile = message::Profile {
+      id: Vec::from("user"),
+      username: None,
+      nick: None,
+    };
+    let message = Message::new(profile, 1, vec![], "channel", PLATFORM_TELEGRAM);
+    let packet = Packet::from(message.to_left()).unwrap();
+    assert!(matches!(packet.as_event(), Err(DataError::NotAnEvent)));
+  }
+
+  #[test]
+  fn json() {
+    init_cipher();
+    let event = Event::RequestEcho {
+      name: "echo".into(),
+    };
+    let packet = Packet::from(event.to_right()).unwrap().with_ttl(60);
+    let from_json = Packet::from_json(&packet.to_json().unwrap()).unwrap();
+    let from_cbor: Packet = serde_cbor::from_slice(&serde_cbor::to_vec(&packet).unwrap()).unwrap();
+    assert_eq!(
+      serde_cbor::to_vec(&from_json).unwrap(),
+      serde_cbor::to_vec(&from_cbor).unwrap()
+    );
+    assert!(matches!(from_json.into_event(), Ok(Event::RequestEcho { .. })));
+  }
+
+  #[test]
+  fn compression() {
+    init_cipher();
+    let event = Event::RequestEcho {
+      name: "echo".repeat(100).into(),
+    };
+    let packet = Packet::from(event.to_right()).unwrap();
+    let size = packet.size_bytes();
+    let packet = packet.with_compression(true);
+    assert!(packet.compressed);
+    assert!(packet.size_bytes() < size);
+    match Packet::from_cbor(&packet.to_cbor().unwrap()).unwrap().right() {
+      Some(Event::RequestEcho { name }) => assert_eq!(name, "echo".repeat(100)),
+      _ => panic!("expected RequestEcho"),
+    }
+  }
+
+  #[test]
+  fn id_from_bytes() {
+    let id = new_id_from_bytes(b"content");
+    assert_eq!(id, new_id_from_bytes(b"content"));
+    assert_ne!(id, new_id_from_bytes(b"another content"));
+    assert_eq!(id.get_version_num(), 4);
+  }
+
+  #[test]
+  fn missing_channel() {
+    init_cipher();
+    let profile = message::Profile {
+      id: Vec::from("user"),
+      username: None,
+      nick: None,
+    };
+    let message = Message::new(profile, 1, vec![], "", PLATFORM_TELEGRAM);
+    let packet = Packet::from(message.to_left()).unwrap();
+    let cbor_packet = serde_cbor::to_vec(&packet).unwrap();
+    let err = Packet::from_cbor(&cbor_packet).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<DataError>(),
+      Some(DataError::MissingChannel)
+    ));
+  }
 }
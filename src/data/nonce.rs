@@ -0,0 +1,55 @@
+use std::collections::{HashSet, VecDeque};
+
+// remembers the most recently seen message nonces to drop messages delivered
+// twice over different relay hops. A nonce is only meaningful within a single
+// channel, so keep one tracker per channel.
+pub struct NonceTracker {
+  capacity: usize,
+  seen: HashSet<u64>,
+  // least recently seen first
+  order: VecDeque<u64>,
+}
+impl NonceTracker {
+  pub fn new(capacity: usize) -> Self {
+    NonceTracker {
+      capacity: capacity.max(1),
+      seen: HashSet::with_capacity(capacity),
+      order: VecDeque::with_capacity(capacity),
+    }
+  }
+
+  // true when nonce is new, false for a duplicate
+  pub fn check(&mut self, nonce: u64) -> bool {
+    if self.seen.contains(&nonce) {
+      if let Some(pos) = self.order.iter().position(|n| *n == nonce) {
+        self.order.remove(pos);
+      }
+      self.order.push_back(nonce);
+      return false;
+    }
+    if self.order.len() == self.capacity {
+      if let Some(oldest) = self.order.pop_front() {
+        self.seen.remove(&oldest);
+      }
+    }
+    self.seen.insert(nonce);
+    self.order.push_back(nonce);
+    true
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::data::nonce::NonceTracker;
+  #[test]
+  fn test() {
+    let mut tracker = NonceTracker::new(2);
+    assert!(tracker.check(1));
+    assert!(tracker.check(2));
+    assert!(!tracker.check(1));
+    // 2 is the least recently seen now
+    assert!(tracker.check(3));
+    assert!(!tracker.check(1));
+    assert!(tracker.check(2));
+  }
+}
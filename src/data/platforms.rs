@@ -0,0 +1,14 @@
+use arcstr::ArcStr;
+
+pub const PLATFORM_DISCORD: ArcStr = arcstr::literal!("discord");
+pub const PLATFORM_TELEGRAM: ArcStr = arcstr::literal!("telegram");
+pub const PLATFORM_QQ: ArcStr = arcstr::literal!("qq");
+pub const PLATFORM_WECHAT: ArcStr = arcstr::literal!("wechat");
+pub const PLATFORM_WHATSAPP: ArcStr = arcstr::literal!("whatsapp");
+pub const PLATFORM_MINECRAFT: ArcStr = arcstr::literal!("minecraft");
+// packets of older versions carry no platform
+pub const PLATFORM_UNKNOWN: ArcStr = arcstr::literal!("unknown");
+
+pub(crate) fn unknown() -> ArcStr {
+  PLATFORM_UNKNOWN
+}
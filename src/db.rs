@@ -1,28 +1,75 @@
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use arcstr::ArcStr;
 use color_eyre::eyre::Result;
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
 use lateinit::LateInit;
-use sled::IVec;
-use tracing::error;
+use sled::{
+  transaction::{TransactionError, TransactionResult},
+  IVec, Transactional,
+};
+use tracing::{error, info};
+
+use crate::net::Validators;
+
+// trees of ttl_put are prefixed so the cleanup finds them
+const TTL_TREE_PREFIX: &str = "ttl:";
+const TTL_CLEANUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Default)]
+pub struct BackupReport {
+  // bytes of keys and values exported, by "<db>/<tree>"
+  pub trees: HashMap<String, u64>,
+}
 
 #[derive(Singleton, Default)]
 pub struct Db {
   image_db: LateInit<sled::Db>,
+  // url -> sha256 of the content
+  url_hash_tree: LateInit<sled::Tree>,
+  // file id -> uid, the reverse of image_db
+  file_id_tree: LateInit<sled::Tree>,
+  // url -> cache validators of the content
+  url_validators_tree: LateInit<sled::Tree>,
   // message id
   mid_db_map: DashMap<Vec<u8>, sled::Db>,
 
-  db_name: LateInit<ArcStr>,
+  // db/<db_name> unless opened elsewhere
+  db_path: LateInit<PathBuf>,
 }
 impl Db {
   pub fn init(&self, db_name: Option<ArcStr>) {
     let db_name = db_name.unwrap_or_else(|| ArcStr::from("default"));
+    self.init_in(Path::new("db"), db_name);
+    spawn_ttl_cleanup((*self.image_db).clone());
+  }
+
+  // a db in a temporary directory, removed when the returned TempDir drops
+  #[cfg(any(test, feature = "test-utils"))]
+  pub fn open_in_memory() -> (Db, tempfile::TempDir) {
+    let dir = tempfile::tempdir().expect("failed to create temporary directory");
+    let db = Db::default();
+    db.init_in(dir.path(), ArcStr::from("test"));
+    (db, dir)
+  }
 
+  fn init_in(&self, root: &Path, db_name: ArcStr) {
+    let db_path = root.join(db_name.as_str());
     let options = sled::Config::default().cache_capacity(1024 * 1024);
-    let image_db_path = format!("db/{}/image", db_name);
-    let image_db = options.path(image_db_path.as_str()).open().unwrap();
+    let image_db = options.path(db_path.join("image")).open().unwrap();
+    let url_hash_tree = image_db.open_tree("url_to_hash").unwrap();
+    self.url_hash_tree.init(url_hash_tree);
+    let file_id_tree = image_db.open_tree("file_id_to_uid").unwrap();
+    self.file_id_tree.init(file_id_tree);
+    let url_validators_tree = image_db.open_tree("url_validators").unwrap();
+    self.url_validators_tree.init(url_validators_tree);
     self.image_db.init(image_db);
 
-    self.db_name.init(db_name);
+    self.db_path.init(db_path);
   }
 
   pub fn put_image_id<U, F>(&self, uid: U, file_id: F)
@@ -30,7 +77,77 @@ impl Db {
     U: AsRef<[u8]>,
     F: Into<IVec>,
   {
-    self.image_db.insert(uid, file_id).unwrap();
+    let file_id = file_id.into();
+    self.image_db.insert(&uid, file_id.clone()).unwrap();
+    self.file_id_tree.insert(file_id, uid.as_ref()).unwrap();
+  }
+
+  // inserts all items in one sled batch, much cheaper than one by one, see
+  // benches/db.rs, the reverse tree is updated in the same transaction
+  pub fn put_image_id_many<I, U, F>(&self, items: I) -> sled::Result<()>
+  where
+    I: IntoIterator<Item = (U, F)>,
+    U: AsRef<[u8]>,
+    F: Into<IVec>,
+  {
+    let mut batch = sled::Batch::default();
+    let mut reverse = sled::Batch::default();
+    for (uid, file_id) in items {
+      let file_id = file_id.into();
+      batch.insert(uid.as_ref(), file_id.clone());
+      reverse.insert(file_id, uid.as_ref());
+    }
+    let image_tree: &sled::Tree = &self.image_db;
+    let trees = (image_tree, &*self.file_id_tree);
+    let result: TransactionResult<(), ()> = trees.transaction(|(images, file_ids)| {
+      images.apply_batch(&batch)?;
+      file_ids.apply_batch(&reverse)?;
+      Ok(())
+    });
+    result.map_err(|e| match e {
+      // the transaction never aborts
+      TransactionError::Abort(()) => unreachable!(),
+      TransactionError::Storage(e) => e,
+    })
+  }
+
+  // returns whether file_id was inserted, an existing id is kept
+  pub fn put_image_id_if_absent<U, F>(&self, uid: U, file_id: F) -> bool
+  where
+    U: AsRef<[u8]>,
+    F: Into<IVec>,
+  {
+    let file_id = file_id.into();
+    let inserted = self
+      .image_db
+      .compare_and_swap(&uid, None as Option<&[u8]>, Some(file_id.clone()))
+      .unwrap()
+      .is_ok();
+    if inserted {
+      self.file_id_tree.insert(file_id, uid.as_ref()).unwrap();
+    }
+    inserted
+  }
+
+  // moves the file id of old_uid to new_uid in one transaction, false when
+  // old_uid has none
+  pub fn rename_image_id(&self, old_uid: &[u8], new_uid: &[u8]) -> sled::Result<bool> {
+    let image_tree: &sled::Tree = &self.image_db;
+    let trees = (image_tree, &*self.file_id_tree);
+    let result: TransactionResult<bool, ()> = trees.transaction(|(images, reverse)| {
+      let file_id = match images.remove(old_uid)? {
+        Some(v) => v,
+        None => return Ok(false),
+      };
+      images.insert(new_uid, file_id.clone())?;
+      reverse.insert(file_id, new_uid)?;
+      Ok(true)
+    });
+    result.map_err(|e| match e {
+      // the transaction never aborts
+      TransactionError::Abort(()) => unreachable!(),
+      TransactionError::Storage(e) => e,
+    })
   }
 
   pub fn get_image_id<T>(&self, uid: T) -> Option<IVec>
@@ -46,6 +163,133 @@ impl Db {
     }
   }
 
+  pub fn get_uid_by_file_id<F>(&self, file_id: F) -> Option<Vec<u8>>
+  where
+    F: AsRef<[u8]>,
+  {
+    match self.file_id_tree.get(file_id) {
+      Ok(uid) => uid.map(|uid| uid.to_vec()),
+      Err(e) => {
+        error!("{:?}", e);
+        None
+      }
+    }
+  }
+
+  // uids of every image with a recorded file id
+  pub fn image_uids(&self) -> Vec<Vec<u8>> {
+    self
+      .image_db
+      .iter()
+      .keys()
+      .filter_map(|key| match key {
+        Ok(key) => Some(key.to_vec()),
+        Err(e) => {
+          error!("{:?}", e);
+          None
+        }
+      })
+      .collect()
+  }
+
+  pub fn put_url_hash<U, H>(&self, url: U, hash: H) -> Result<()>
+  where
+    U: AsRef<[u8]>,
+    H: Into<IVec>,
+  {
+    self.url_hash_tree.insert(url, hash)?;
+    Ok(())
+  }
+
+  pub fn get_url_hash<U>(&self, url: U) -> Option<IVec>
+  where
+    U: AsRef<[u8]>,
+  {
+    match self.url_hash_tree.get(url) {
+      Ok(hash) => hash,
+      Err(e) => {
+        error!("{:?}", e);
+        None
+      }
+    }
+  }
+
+  pub fn put_url_validators<U>(&self, url: U, validators: &Validators) -> Result<()>
+  where
+    U: AsRef<[u8]>,
+  {
+    self
+      .url_validators_tree
+      .insert(url, serde_cbor::to_vec(validators)?)?;
+    Ok(())
+  }
+
+  pub fn get_url_validators<U>(&self, url: U) -> Option<Validators>
+  where
+    U: AsRef<[u8]>,
+  {
+    let value = match self.url_validators_tree.get(url) {
+      Ok(value) => value?,
+      Err(e) => {
+        error!("{:?}", e);
+        return None;
+      }
+    };
+    serde_cbor::from_slice(&value).ok()
+  }
+
+  // stores value in tree until ttl passed, the value is prefixed with the
+  // expiry in big-endian unix seconds
+  pub fn ttl_put(&self, tree: &str, key: &[u8], value: &[u8], ttl: Duration) -> sled::Result<()> {
+    let expire_at = unix_secs() + ttl.as_secs();
+    let mut entry = expire_at.to_be_bytes().to_vec();
+    entry.extend_from_slice(value);
+    self.ttl_tree(tree)?.insert(key, entry)?;
+    Ok(())
+  }
+
+  // None for missing and expired entries, expired entries are removed by the
+  // cleanup later
+  pub fn ttl_get(&self, tree: &str, key: &[u8]) -> sled::Result<Option<IVec>> {
+    let entry = match self.ttl_tree(tree)?.get(key)? {
+      Some(v) => v,
+      None => return Ok(None),
+    };
+    if is_expired(&entry, unix_secs()) {
+      return Ok(None);
+    }
+    Ok(Some(entry.subslice(8, entry.len() - 8)))
+  }
+
+  fn ttl_tree(&self, tree: &str) -> sled::Result<sled::Tree> {
+    self
+      .image_db
+      .open_tree(format!("{}{}", TTL_TREE_PREFIX, tree))
+  }
+
+  // uid -> photo url
+  pub fn put_cached_url<U>(&self, uid: U, url: &ArcStr, ttl: Duration) -> Result<()>
+  where
+    U: AsRef<[u8]>,
+  {
+    self.ttl_put("url_cache", uid.as_ref(), url.as_bytes(), ttl)?;
+    Ok(())
+  }
+
+  pub fn get_cached_url<U>(&self, uid: U) -> Option<ArcStr>
+  where
+    U: AsRef<[u8]>,
+  {
+    let url = match self.ttl_get("url_cache", uid.as_ref()) {
+      Ok(url) => url?,
+      Err(e) => {
+        error!("{:?}", e);
+        return None;
+      }
+    };
+    std::str::from_utf8(&url).ok().map(ArcStr::from)
+  }
+
   pub fn put_msg_id(
     &self,
     target: Vec<u8>,
@@ -54,13 +298,8 @@ impl Db {
     reverse: bool,
   ) -> Result<()> {
     let msg_id_db = self.mid_db_map.entry(target.clone()).or_insert_with(|| {
-      let options = sled::Config::default().cache_capacity(1024 * 1024);
-      let msg_id_db_path = format!(
-        "db/{}/msg-id/{}",
-        *self.db_name,
-        base64_url::encode(&target)
-      );
-      options.path(msg_id_db_path).open().unwrap()
+      let msg_id_db_path = self.msg_id_dir().join(base64_url::encode(&target));
+      open_msg_id_db(&msg_id_db_path).unwrap()
     });
     msg_id_db.insert(&uid, id.clone())?;
     if reverse {
@@ -80,4 +319,180 @@ impl Db {
     };
     Ok(Some(id))
   }
+
+  fn msg_id_dir(&self) -> PathBuf {
+    self.db_path.join("msg-id")
+  }
+
+  // msg id dbs are opened on their first put_msg_id, this opens the ones of
+  // targets not written to since start
+  fn load_msg_id_dbs(&self) -> sled::Result<()> {
+    let entries = match std::fs::read_dir(self.msg_id_dir()) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+      let entry = entry?;
+      if !entry.file_type()?.is_dir() {
+        continue;
+      }
+      let name = entry.file_name();
+      let target = match base64_url::decode(name.to_string_lossy().as_bytes()) {
+        Ok(target) => target,
+        Err(_) => continue,
+      };
+      if let Entry::Vacant(vacant) = self.mid_db_map.entry(target) {
+        vacant.insert(open_msg_id_db(&entry.path())?);
+      }
+    }
+    Ok(())
+  }
+
+  pub fn size_on_disk(&self) -> sled::Result<u64> {
+    self.load_msg_id_dbs()?;
+    let mut size = self.image_db.size_on_disk()?;
+    for msg_id_db in self.mid_db_map.iter() {
+      size += msg_id_db.size_on_disk()?;
+    }
+    Ok(size)
+  }
+
+  // flushes all dbs so sled can reclaim the space held by dead pages
+  pub fn compact(&self) -> sled::Result<()> {
+    let before = self.size_on_disk()?;
+    self.image_db.flush()?;
+    for msg_id_db in self.mid_db_map.iter() {
+      msg_id_db.flush()?;
+    }
+    let after = self.size_on_disk()?;
+    info!("Compacted db, freed {} bytes", before.saturating_sub(after));
+    Ok(())
+  }
+
+  // exports a snapshot of every db into dest, written to dest.tmp first and
+  // renamed once complete
+  pub async fn backup(&self, dest: PathBuf) -> Result<BackupReport> {
+    self.load_msg_id_dbs()?;
+    let mut sources = vec![("image".to_string(), self.image_db.clone())];
+    for msg_id_db in self.mid_db_map.iter() {
+      let name = format!("msg-id/{}", base64_url::encode(msg_id_db.key()));
+      sources.push((name, msg_id_db.value().clone()));
+    }
+    tokio::task::spawn_blocking(move || -> Result<BackupReport> {
+      let tmp_dest = dest.with_extension("tmp");
+      if tmp_dest.exists() {
+        std::fs::remove_dir_all(&tmp_dest)?;
+      }
+      let mut report = BackupReport::default();
+      for (name, src) in sources {
+        let dst = sled::Config::default().path(tmp_dest.join(&name)).open()?;
+        for tree_name in src.tree_names() {
+          let src_tree = src.open_tree(&tree_name)?;
+          let dst_tree = dst.open_tree(&tree_name)?;
+          let mut batch = sled::Batch::default();
+          let mut bytes = 0;
+          for kv in src_tree.iter() {
+            let (key, value) = kv?;
+            bytes += (key.len() + value.len()) as u64;
+            batch.insert(key, value);
+          }
+          dst_tree.apply_batch(batch)?;
+          let tree_name = String::from_utf8_lossy(&tree_name);
+          report.trees.insert(format!("{}/{}", name, tree_name), bytes);
+        }
+        dst.flush()?;
+      }
+      std::fs::rename(&tmp_dest, &dest)?;
+      Ok(report)
+    })
+    .await?
+  }
+}
+
+fn open_msg_id_db(path: &Path) -> sled::Result<sled::Db> {
+  sled::Config::default()
+    .cache_capacity(1024 * 1024)
+    .path(path)
+    .open()
+}
+
+fn unix_secs() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or_default()
+}
+
+// entries too short to hold the expiry are treated as expired
+fn is_expired(entry: &[u8], now: u64) -> bool {
+  match entry.get(..8) {
+    Some(expire_at) => u64::from_be_bytes(expire_at.try_into().unwrap()) <= now,
+    None => true,
+  }
+}
+
+fn spawn_ttl_cleanup(db: sled::Db) {
+  std::thread::spawn(move || loop {
+    std::thread::sleep(TTL_CLEANUP_INTERVAL);
+    if let Err(e) = remove_expired(&db) {
+      error!("Failed to remove expired entries: {:?}", e);
+    }
+  });
+}
+
+fn remove_expired(db: &sled::Db) -> sled::Result<()> {
+  let now = unix_secs();
+  for name in db.tree_names() {
+    if !name.starts_with(TTL_TREE_PREFIX.as_bytes()) {
+      continue;
+    }
+    let tree = db.open_tree(&name)?;
+    for entry in tree.iter() {
+      let (key, value) = entry?;
+      if is_expired(&value, now) {
+        tree.remove(key)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use crate::db::{open_msg_id_db, Db};
+
+  #[test]
+  fn open_in_memory() {
+    let (db, _dir) = Db::open_in_memory();
+    db.put_image_id("uid", "file_id");
+    assert_eq!(db.get_image_id("uid").unwrap(), "file_id");
+    assert_eq!(db.get_uid_by_file_id("file_id").unwrap(), b"uid");
+    assert!(db.rename_image_id(b"uid", b"new_uid").unwrap());
+    assert_eq!(db.get_image_id("new_uid").unwrap(), "file_id");
+    assert!(db.get_image_id("uid").is_none());
+  }
+
+  #[test]
+  fn put_image_id_many() {
+    let (db, _dir) = Db::open_in_memory();
+    let items = (0..100u32).map(|i| (i.to_be_bytes(), format!("file_id_{}", i).into_bytes()));
+    db.put_image_id_many(items).unwrap();
+    for i in 0..100u32 {
+      let file_id = format!("file_id_{}", i);
+      assert_eq!(db.get_image_id(i.to_be_bytes()).unwrap(), file_id.as_bytes());
+      assert_eq!(db.get_uid_by_file_id(&file_id).unwrap(), i.to_be_bytes());
+    }
+    assert_eq!(db.image_uids().len(), 100);
+  }
+
+  #[test]
+  fn load_msg_id_dbs() {
+    let (db, _dir) = Db::open_in_memory();
+    // a msg id db of an earlier run, not opened by put_msg_id
+    let path = db.msg_id_dir().join(base64_url::encode(b"target"));
+    open_msg_id_db(&path).unwrap().flush().unwrap();
+    db.size_on_disk().unwrap();
+    assert!(db.mid_db_map.contains_key(b"target".as_slice()));
+  }
 }
@@ -1 +1,81 @@
+use thiserror::Error;
 
+#[derive(Error, Debug)]
+pub enum DataError {
+  #[error("Invalid packet: {0}")]
+  InvalidPacket(String),
+  #[error("Unexpected response: {0}")]
+  UnexpectedResponse(String),
+  #[error("Message without channel")]
+  MissingChannel,
+  #[error("Packet is not signed")]
+  SignatureMissing,
+  #[error("Packet signature doesn't match")]
+  SignatureInvalid,
+  #[error("Packet holds a message, not an event")]
+  NotAnEvent,
+  #[error("Malformed packet: {0}")]
+  Malformed(String),
+}
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+  #[error("Corrupted packet of {raw_len} bytes: {detail}")]
+  CorruptedPacket { raw_len: usize, detail: String },
+  #[error("Expected {expected} content but got {actual}")]
+  ContentTypeMismatch { expected: String, actual: String },
+}
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+  #[error("Packet expired {age_secs}s after its creation, ttl is {ttl}s")]
+  PacketExpired { age_secs: u64, ttl: u32 },
+  #[error("Too many requests in flight")]
+  Backpressure,
+  #[error("Circuit breaker of the address is open")]
+  CircuitOpen,
+  #[error("No reply within {0:?}")]
+  Timeout(std::time::Duration),
+  #[error("Transport error: {0}")]
+  Transport(String),
+  #[error("The subscription has been unsubscribed or the connection is closed")]
+  ConnectionClosed,
+}
+impl ServerError {
+  // failures of the transport, worth another attempt
+  pub fn is_transient(&self) -> bool {
+    matches!(
+      self,
+      ServerError::Timeout(_) | ServerError::Transport(_) | ServerError::ConnectionClosed
+    )
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum WaitForError {
+  #[error("Timed out waiting for the file")]
+  Timeout,
+  #[error("The file is no longer awaited")]
+  SenderDropped,
+}
+
+#[derive(Error, Debug)]
+pub enum NetError {
+  #[error("Invalid url {0}")]
+  InvalidUrl(String),
+  #[error("Http error: {0}")]
+  HttpError(#[source] reqwest::Error),
+  #[error("Request timed out")]
+  Timeout,
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+}
+impl From<reqwest::Error> for NetError {
+  fn from(e: reqwest::Error) -> Self {
+    if e.is_timeout() {
+      NetError::Timeout
+    } else {
+      NetError::HttpError(e)
+    }
+  }
+}
@@ -1,14 +1,14 @@
 #![feature(fn_traits, trait_alias, backtrace)]
 use arcstr::ArcStr;
-use cache::CACHE;
+use cache::{CacheOptions, CacheStats, CACHE};
 use cipher::CIPHER;
 use color_eyre::eyre::Result;
 use db::DB;
 use educe::Educe;
 use futures::future::BoxFuture;
-use net::NET;
+use net::{NetOptions, NET};
 use res::RES;
-use server::SERVER;
+use server::{ServerMetrics, SERVER};
 use sled::IVec;
 
 pub mod cache;
@@ -38,6 +38,15 @@ pub struct MesagistoConfig {
   #[educe(Default = "nats://itsusinn.site:4222")]
   pub nats_address: ArcStr,
   pub photo_url_resolver: Option<Box<Handler>>,
+  // deduplicate downloaded files by the sha256 of their content
+  pub content_addressed: bool,
+  #[educe(Default = 100)]
+  pub batch_limit: usize,
+  pub net_options: NetOptions,
+  pub cache_options: CacheOptions,
+  // see data::platforms
+  #[educe(Default(expression = "data::platforms::PLATFORM_UNKNOWN"))]
+  pub platform: ArcStr,
 }
 impl MesagistoConfig {
   pub fn builder() -> MesagistoConfigBuilder {
@@ -46,14 +55,16 @@ impl MesagistoConfig {
 
   pub async fn apply(self) -> Result<()> {
     DB.init(self.name.some());
-    CACHE.init();
+    CACHE.init(self.content_addressed, self.cache_options);
     CIPHER.init(&self.cipher_key);
     RES.init().await;
-    RES
-      .photo_url_resolver
-      .init(self.photo_url_resolver.unwrap());
-    SERVER.init(&self.nats_address).await?;
-    NET.init(self.proxy);
+    if let Some(resolver) = self.photo_url_resolver {
+      RES.store_photo_url_resolver(resolver);
+    }
+    SERVER
+      .init(&self.nats_address, self.batch_limit, self.platform)
+      .await?;
+    NET.init(self.proxy, self.net_options);
     Ok(())
   }
 }
@@ -86,6 +97,31 @@ impl MesagistoConfigBuilder {
     self
   }
 
+  pub fn content_addressed(mut self, content_addressed: bool) -> Self {
+    self.config.content_addressed = content_addressed;
+    self
+  }
+
+  pub fn batch_limit(mut self, limit: usize) -> Self {
+    self.config.batch_limit = limit;
+    self
+  }
+
+  pub fn net_options(mut self, options: NetOptions) -> Self {
+    self.config.net_options = options;
+    self
+  }
+
+  pub fn platform(mut self, platform: impl Into<ArcStr>) -> Self {
+    self.config.platform = platform.into();
+    self
+  }
+
+  pub fn cache_options(mut self, options: CacheOptions) -> Self {
+    self.config.cache_options = options;
+    self
+  }
+
   pub fn photo_url_resolver<F>(mut self, resolver: F) -> Self
   where
     F: Fn(&(Vec<u8>, IVec)) -> BoxFuture<Result<ArcStr>> + Send + Sync + 'static,
@@ -100,6 +136,19 @@ impl MesagistoConfigBuilder {
   }
 }
 
+#[derive(Debug, Clone)]
+pub struct MesagistoMetrics {
+  pub server: ServerMetrics,
+  pub cache: CacheStats,
+}
+
+pub fn mesagisto_metrics() -> MesagistoMetrics {
+  MesagistoMetrics {
+    server: SERVER.metrics(),
+    cache: CACHE.stats(),
+  }
+}
+
 // R refers to <Return>
 pub trait RunExt<R> {
   // let is a keyword in rust,so...let's use ret
@@ -2,9 +2,16 @@ use std::{path::PathBuf, time::Duration};
 
 use arcstr::ArcStr;
 use color_eyre::eyre::Result;
-use futures::FutureExt;
+use educe::Educe;
 use lateinit::LateInit;
-use tokio::io::AsyncWriteExt;
+use reqwest::{
+  header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+  Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{error::NetError, OkExt};
 
 pub fn new_reqwest_builder() -> reqwest::ClientBuilder {
   let connect_timeout = Duration::from_secs(5);
@@ -17,39 +24,217 @@ pub fn new_reqwest_builder() -> reqwest::ClientBuilder {
     .use_rustls_tls()
 }
 
+#[derive(Educe, Clone)]
+#[educe(Default)]
+pub struct NetOptions {
+  // timeout of requests probing a url without downloading it
+  #[educe(Default(expression = "Duration::from_secs(5)"))]
+  pub probe_timeout: Duration,
+  // redirects followed before a download fails
+  #[educe(Default = 10)]
+  pub max_redirects: usize,
+}
+
+pub trait ProgressSink: Send + Sync {
+  // bytes downloaded so far, and the total when the server announced it
+  fn on_bytes(&self, downloaded: u64, total: Option<u64>);
+}
+
+// cache validators of a downloaded url, sent back to revalidate it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Validators {
+  pub etag: Option<String>,
+  pub last_modified: Option<String>,
+}
+impl Validators {
+  pub fn is_empty(&self) -> bool {
+    self.etag.is_none() && self.last_modified.is_none()
+  }
+
+  fn from_headers(headers: &HeaderMap) -> Self {
+    let header = |name| {
+      headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+    };
+    Validators {
+      etag: header(ETAG),
+      last_modified: header(LAST_MODIFIED),
+    }
+  }
+}
+
 #[derive(Singleton, Default)]
 pub struct Net {
   inner: LateInit<reqwest::Client>,
+  proxy: LateInit<Option<ArcStr>>,
+  options: LateInit<NetOptions>,
 }
 impl Net {
-  pub fn init(&self, proxy: Option<ArcStr>) {
+  pub fn init(&self, proxy: Option<ArcStr>, options: NetOptions) {
+    self.proxy.init(proxy);
+    self.inner.init(self.build_client(options.max_redirects));
+    self.options.init(options);
+  }
+
+  fn build_client(&self, max_redirects: usize) -> reqwest::Client {
     let builder = new_reqwest_builder();
-    let builder = if let Some(proxy) = proxy {
+    let builder = if let Some(proxy) = &*self.proxy {
       builder.proxy(reqwest::Proxy::all(proxy.as_str()).expect("reqwest::Proxy create failed"))
     } else {
       builder
     };
-    self.inner.init(
-      builder
-        .gzip(true)
-        .build()
-        .expect("reqwest::Client create failed"),
-    );
+    builder
+      .gzip(true)
+      .redirect(reqwest::redirect::Policy::limited(max_redirects))
+      .build()
+      .expect("reqwest::Client create failed")
   }
 
   pub async fn download(&self, url: &ArcStr, dst: &PathBuf) -> Result<()> {
     let mut dst_file = tokio::fs::File::create(&dst).await?;
-    self
+    self.download_to(url, &mut dst_file, None).await
+  }
+
+  // like download but following at most max_redirects redirects, instead of
+  // NetOptions::max_redirects
+  pub async fn download_with_redirects_limit(
+    &self,
+    url: &ArcStr,
+    path: &PathBuf,
+    max_redirects: usize,
+  ) -> Result<(), NetError> {
+    let client = if max_redirects == self.options.max_redirects {
+      (*self.inner).clone()
+    } else {
+      self.build_client(max_redirects)
+    };
+    let res = client.get(url.as_str()).send().await?.error_for_status()?;
+    let mut dst_file = tokio::fs::File::create(path).await?;
+    write_body(res, &mut dst_file, None).await
+  }
+
+  // the client configured in init, e.g. to clone a client with another proxy
+  // from it
+  pub fn client(&self) -> &reqwest::Client {
+    &self.inner
+  }
+
+  pub async fn download_to<W>(
+    &self,
+    url: &ArcStr,
+    sink: &mut W,
+    progress: Option<&dyn ProgressSink>,
+  ) -> Result<()>
+  where
+    W: AsyncWrite + Unpin,
+  {
+    self.download_to_with(&self.inner, url, sink, progress).await
+  }
+
+  pub async fn download_to_with<W>(
+    &self,
+    client: &reqwest::Client,
+    url: &ArcStr,
+    sink: &mut W,
+    progress: Option<&dyn ProgressSink>,
+  ) -> Result<()>
+  where
+    W: AsyncWrite + Unpin,
+  {
+    let res = client
+      .get(url.as_str())
+      .send()
+      .await?
+      .error_for_status()?;
+    Ok(write_body(res, sink, progress).await?)
+  }
+
+  // downloads url into sink unless the server answers 304 Not Modified to the
+  // validators, returns the new validators or None when not modified
+  pub async fn download_to_if_modified<W>(
+    &self,
+    client: &reqwest::Client,
+    url: &ArcStr,
+    sink: &mut W,
+    validators: &Validators,
+    progress: Option<&dyn ProgressSink>,
+  ) -> Result<Option<Validators>>
+  where
+    W: AsyncWrite + Unpin,
+  {
+    let mut req = client.get(url.as_str());
+    if let Some(etag) = &validators.etag {
+      req = req.header(IF_NONE_MATCH, etag.as_str());
+    }
+    if let Some(last_modified) = &validators.last_modified {
+      req = req.header(IF_MODIFIED_SINCE, last_modified.as_str());
+    }
+    let res = req.send().await?;
+    if res.status() == StatusCode::NOT_MODIFIED {
+      return Ok(None);
+    }
+    let res = res.error_for_status()?;
+    let validators = Validators::from_headers(res.headers());
+    write_body(res, sink, progress).await?;
+    Ok(Some(validators))
+  }
+
+  // for callers that need the whole body in memory
+  pub async fn download_to_bytes(&self, url: &ArcStr) -> Result<bytes::Bytes> {
+    let res = self
       .inner
       .get(url.as_str())
       .send()
-      .then(move |r| async move {
-        let mut res = r?.error_for_status()?;
-        while let Some(chunk) = res.chunk().await? {
-          dst_file.write_all(&chunk).await?;
-        }
-        Ok(())
-      })
-      .await
+      .await?
+      .error_for_status()?;
+    Ok(res.bytes().await?)
+  }
+
+  // response headers of url without downloading the body, e.g. ETag or
+  // Last-Modified
+  pub async fn head_request(&self, url: &ArcStr) -> Result<HeaderMap, NetError> {
+    let url = reqwest::Url::parse(url).map_err(|_| NetError::InvalidUrl(url.to_string()))?;
+    let res = self
+      .inner
+      .head(url)
+      .timeout(self.options.probe_timeout)
+      .send()
+      .await?
+      .error_for_status()?;
+    Ok(res.headers().clone())
+  }
+
+  pub async fn get_content_type(&self, url: &ArcStr) -> Result<Option<ArcStr>> {
+    self
+      .head_request(url)
+      .await?
+      .get(reqwest::header::CONTENT_TYPE)
+      .and_then(|v| v.to_str().ok())
+      .map(ArcStr::from)
+      .ok()
+  }
+}
+
+// streams the response body chunk by chunk into sink
+async fn write_body<W>(
+  mut res: Response,
+  sink: &mut W,
+  progress: Option<&dyn ProgressSink>,
+) -> Result<(), NetError>
+where
+  W: AsyncWrite + Unpin,
+{
+  let total = res.content_length();
+  let mut downloaded = 0;
+  while let Some(chunk) = res.chunk().await? {
+    sink.write_all(&chunk).await?;
+    downloaded += chunk.len() as u64;
+    if let Some(progress) = progress {
+      progress.on_bytes(downloaded, total);
+    }
   }
+  sink.flush().await?;
+  Ok(())
 }
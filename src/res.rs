@@ -1,46 +1,66 @@
-use std::path::PathBuf;
+use std::{
+  collections::HashSet,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+  },
+  time::Duration,
+};
 
+use arc_swap::{ArcSwap, ArcSwapOption};
 use arcstr::ArcStr;
 use color_eyre::eyre::Result;
 use dashmap::DashMap;
 use futures::future::BoxFuture;
-use lateinit::LateInit;
 use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use sled::IVec;
-use tokio::sync::{mpsc::channel, oneshot};
+use tokio::{
+  sync::{mpsc::channel, oneshot, RwLock as AsyncRwLock, RwLockReadGuard},
+  task::JoinHandle,
+};
 use tracing::error;
 
-use crate::{db::DB, OptionExt};
+use crate::{db::DB, error::WaitForError, LogResultExt, OptionExt};
+
+// how long a resolved photo url is reused before resolving it again
+const PHOTO_URL_TTL: Duration = Duration::from_secs(30 * 60);
 
 type Handler = dyn Fn(&(Vec<u8>, IVec)) -> BoxFuture<Result<ArcStr>> + Send + Sync + 'static;
+type FileCallback = dyn Fn(ArcStr, PathBuf) + Send + Sync + 'static;
 
 #[derive(Singleton, Default)]
 pub struct Res {
-  pub directory: LateInit<PathBuf>,
-  pub handlers: LateInit<DashMap<ArcStr, Vec<oneshot::Sender<PathBuf>>>>,
-  pub photo_url_resolver: LateInit<Box<Handler>>,
+  directory: ArcSwap<PathBuf>,
+  handlers: DashMap<ArcStr, Vec<oneshot::Sender<PathBuf>>>,
+  photo_url_resolver: ArcSwapOption<Box<Handler>>,
+  file_created_callback: ArcSwapOption<Box<FileCallback>>,
+  // number of file creation events seen by the watcher
+  watcher_event_count: Arc<AtomicU64>,
+  watcher: Mutex<Option<JoinHandle<notify::Result<()>>>>,
+  // directories watched besides directory, e.g. shared with another adapter
+  custom_directories: RwLock<Vec<PathBuf>>,
+  // held shared while a tmp file is written and renamed into directory,
+  // set_directory takes it exclusively
+  writing: AsyncRwLock<()>,
 }
 impl Res {
-  async fn poll(&self) -> notify::Result<()> {
+  async fn poll(&self, directory: PathBuf) -> notify::Result<()> {
     let (tx, mut rx) = channel(32);
     let mut watcher = RecommendedWatcher::new(move |res| {
       smol::block_on(async {
         tx.send(res).await.unwrap();
       });
     })?;
-    watcher.watch(self.directory.as_path(), RecursiveMode::NonRecursive)?;
+    watcher.watch(directory.as_path(), RecursiveMode::NonRecursive)?;
     while let Some(res) = rx.recv().await {
       match res {
         Ok(Event { kind, paths, .. }) => {
           if let EventKind::Create(notify::event::CreateKind::File) = kind {
+            self.watcher_event_count.fetch_add(1, Ordering::Relaxed);
             for path in paths {
               let file_name = ArcStr::from(path.file_name().unwrap().to_string_lossy());
-              if self.handlers.contains_key(&file_name) {
-                let (.., handler_list) = self.handlers.remove(&file_name).unwrap();
-                for handler in handler_list {
-                  handler.send(path.clone()).unwrap();
-                }
-              }
+              self.file_ready(&file_name, &path);
             }
           }
         }
@@ -50,18 +70,162 @@ impl Res {
     Ok(())
   }
 
+  // files renamed into the directory, e.g. by with_temp_file, are reported
+  // here by the renaming code, the watcher only sees files created in place
+  pub(crate) fn file_ready(&self, id: &ArcStr, path: &PathBuf) {
+    // tmp files are still being written
+    if !id.ends_with(".tmp") {
+      if let Some(callback) = self.file_created_callback.load_full() {
+        callback(id.clone(), path.clone());
+      }
+    }
+    self.fire_handlers(id, path);
+  }
+
+  // hands path to everyone waiting for id in wait_for
+  pub fn fire_handlers(&self, id: &ArcStr, path: &PathBuf) {
+    if let Some((.., handler_list)) = self.handlers.remove(id) {
+      for handler in handler_list {
+        // the receiver may have timed out already
+        let _ = handler.send(path.clone());
+      }
+    }
+  }
+
+  pub fn watcher_event_count(&self) -> u64 {
+    self.watcher_event_count.load(Ordering::Relaxed)
+  }
+
+  pub fn reset_watcher_event_count(&self) {
+    self.watcher_event_count.store(0, Ordering::Relaxed);
+  }
+
+  fn start_watcher(&self, directory: PathBuf) {
+    let watcher = tokio::spawn(async move { RES.poll(directory).await });
+    if let Some(old) = self.watcher.lock().unwrap().replace(watcher) {
+      old.abort();
+    }
+  }
+
+  // watches directory as well, files created there fire the same handlers and
+  // callback as files created in the cache directory
+  pub fn watch_custom_directory(&self, directory: PathBuf) {
+    {
+      let mut custom_directories = self.custom_directories.write().unwrap();
+      if custom_directories.contains(&directory) {
+        return;
+      }
+      custom_directories.push(directory.clone());
+    }
+    tokio::spawn(async move {
+      if let Err(e) = RES.poll(directory).await {
+        error!("Failed to watch the custom directory: {:?}", e);
+      }
+    });
+  }
+
+  pub fn custom_directories(&self) -> Vec<PathBuf> {
+    self.custom_directories.read().unwrap().clone()
+  }
+
+  // the directory is swapped by set_directory, so this is a snapshot of it
+  pub fn directory_path(&self) -> Arc<PathBuf> {
+    self.directory.load_full()
+  }
+
   pub fn path(&self, id: &ArcStr) -> PathBuf {
-    let mut path = self.directory.clone();
+    let mut path = self.directory_path().to_path_buf();
     path.push(id.as_str());
     path
   }
 
   pub fn tmp_path(&self, id: &ArcStr) -> PathBuf {
-    let mut path = self.directory.clone();
+    let mut path = self.directory_path().to_path_buf();
     path.push(format!("{}.tmp", id));
     path
   }
 
+  // the same id in another format, e.g. an image transcoded for a platform
+  pub fn path_for_ext(&self, id: &ArcStr, ext: &str) -> PathBuf {
+    let mut path = self.directory_path().to_path_buf();
+    path.push(format!("{}.{}", id, ext));
+    path
+  }
+
+  pub fn tmp_path_for_ext(&self, id: &ArcStr, ext: &str) -> PathBuf {
+    let mut path = self.directory_path().to_path_buf();
+    path.push(format!("{}.{}.tmp", id, ext));
+    path
+  }
+
+  // bytes of all files in the directory, including content-addressed ones
+  pub async fn directory_size(&self) -> std::io::Result<u64> {
+    let directory = self.directory_path();
+    let mut size = 0;
+    // files of the directory are hard links of the content-addressed ones
+    let mut seen = HashSet::new();
+    for dir in [directory.to_path_buf(), directory.join("content")] {
+      let mut entries = match tokio::fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e),
+      };
+      while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+          continue;
+        }
+        if let Some(inode) = inode_of(&metadata) {
+          if !seen.insert(inode) {
+            continue;
+          }
+        }
+        size += metadata.len();
+      }
+    }
+    Ok(size)
+  }
+
+  // content-addressed files are kept in a sub directory, so the
+  // non-recursive watcher never sees them
+  pub fn content_path(&self, hash: &ArcStr) -> PathBuf {
+    let mut path = self.directory_path().to_path_buf();
+    path.push("content");
+    path.push(hash.as_str());
+    path
+  }
+
+  // runs f on a fresh tmp file of id, which is renamed to the file of id when f
+  // succeeds and removed when it fails
+  pub async fn with_temp_file<F, R>(&self, id: &ArcStr, f: F) -> Result<R>
+  where
+    F: for<'a> FnOnce(&'a PathBuf) -> BoxFuture<'a, Result<R>>,
+  {
+    let _writing = self.writing().await;
+    let tmp_path = self.tmp_path(id);
+    tokio::fs::File::create(&tmp_path).await?;
+    match f(&tmp_path).await {
+      Ok(r) => {
+        let path = self.path(id);
+        tokio::fs::rename(&tmp_path, &path).await?;
+        self.file_ready(id, &path);
+        Ok(r)
+      }
+      Err(e) => {
+        if let Err(e) = tokio::fs::remove_file(&tmp_path).await {
+          error!("Failed to remove {:?}: {:?}", tmp_path, e);
+        }
+        Err(e)
+      }
+    }
+  }
+
+  // keeps set_directory from moving the directory until dropped, take it
+  // before computing the paths to write to and don't nest it
+  pub(crate) async fn writing(&self) -> RwLockReadGuard<'_, ()> {
+    self.writing.read().await
+  }
+
   pub fn wait_for(&self, id: &ArcStr) -> oneshot::Receiver<PathBuf> {
     let (sender, receiver) = oneshot::channel();
     self
@@ -72,16 +236,121 @@ impl Res {
     receiver
   }
 
+  pub async fn wait_for_with_timeout(
+    &self,
+    id: &ArcStr,
+    timeout: Duration,
+  ) -> Result<PathBuf, WaitForError> {
+    match tokio::time::timeout(timeout, self.wait_for(id)).await {
+      Ok(Ok(path)) => Ok(path),
+      Ok(Err(_)) => Err(WaitForError::SenderDropped),
+      Err(_) => Err(WaitForError::Timeout),
+    }
+  }
+
+  // waits for every id at once, each entry resolves or times out on its own
+  pub async fn wait_for_all(
+    &self,
+    ids: &[ArcStr],
+    timeout: Duration,
+  ) -> Vec<Result<PathBuf, WaitForError>> {
+    let waits = ids.iter().map(|id| self.wait_for_with_timeout(id, timeout));
+    futures::future::join_all(waits).await
+  }
+
+  // gives up waiting for id, e.g. when its download was cancelled, the
+  // receivers get a RecvError, returns their number
+  pub fn remove_all_handlers_for(&self, id: &ArcStr) -> usize {
+    match self.handlers.remove(id) {
+      Some((.., handler_list)) => handler_list.len(),
+      None => 0,
+    }
+  }
+
+  // number of receivers still waiting in wait_for
+  pub fn handlers_count(&self) -> usize {
+    self.handlers.iter().map(|entry| entry.value().len()).sum()
+  }
+
+  pub fn handler_ids(&self) -> Vec<ArcStr> {
+    self.handlers.iter().map(|entry| entry.key().clone()).collect()
+  }
+
   pub async fn init(&self) {
     let path = {
       let mut dir = std::env::temp_dir();
       dir.push("mesagisto");
       dir
     };
-    tokio::fs::create_dir_all(path.as_path()).await.unwrap();
-    self.directory.init(path);
-    self.handlers.init(DashMap::default());
-    tokio::spawn(async { RES.poll().await });
+    self.init_with_directory(path).await;
+  }
+
+  // like init but in a fresh directory removed when the guard drops, so
+  // parallel tests don't share files
+  #[cfg(any(test, feature = "test-utils"))]
+  pub async fn init_test(&self) -> tempfile::TempDir {
+    let dir = tempfile::Builder::new()
+      .prefix("mesagisto-test")
+      .tempdir()
+      .unwrap();
+    self.init_with_directory(dir.path().to_path_buf()).await;
+    dir
+  }
+
+  async fn init_with_directory(&self, path: PathBuf) {
+    tokio::fs::create_dir_all(path.join("content")).await.unwrap();
+    self.directory.store(Arc::new(path.clone()));
+    self.handlers.clear();
+    self.start_watcher(path);
+  }
+
+  // moves all files to new_path and watches it instead, files already moved
+  // are moved back if any move fails. files still being written are waited
+  // for, so they are renamed into the old directory before it is moved
+  pub async fn set_directory(&self, new_path: PathBuf) -> Result<(), std::io::Error> {
+    let _writers = self.writing.write().await;
+    let old_path = self.directory_path();
+    if let Some(watcher) = self.watcher.lock().unwrap().take() {
+      watcher.abort();
+    }
+    match move_files(&old_path, &new_path).await {
+      Ok(()) => {
+        self.directory.store(Arc::new(new_path.clone()));
+        self.start_watcher(new_path);
+        Ok(())
+      }
+      Err(e) => {
+        self.start_watcher(old_path.to_path_buf());
+        Err(e)
+      }
+    }
+  }
+
+  // moves the cached file of old_id to new_id, e.g. when the platform assigned
+  // a new id after a re-upload, the image id recorded for old_id moves along
+  pub async fn rename_id(&self, old_id: &ArcStr, new_id: &ArcStr) -> Result<(), std::io::Error> {
+    let decode = |id: &ArcStr| {
+      base64_url::decode(id.as_str())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    };
+    let old_uid = decode(old_id)?;
+    let new_uid = decode(new_id)?;
+    let old_path = self.path(old_id);
+    let new_path = self.path(new_id);
+    if !old_path.exists() {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("no cached file for {}", old_id),
+      ));
+    }
+    tokio::fs::rename(&old_path, &new_path).await?;
+    if let Err(e) = DB.rename_image_id(&old_uid, &new_uid) {
+      // keep the file where the db still points to
+      tokio::fs::rename(&new_path, &old_path).await?;
+      return Err(e.into());
+    }
+    self.file_ready(new_id, &new_path);
+    Ok(())
   }
 
   pub fn put_image_id<U, F>(&self, uid: U, file_id: F)
@@ -92,12 +361,75 @@ impl Res {
     DB.put_image_id(uid, file_id);
   }
 
+  pub fn get_image_id<U>(&self, uid: U) -> Option<IVec>
+  where
+    U: AsRef<[u8]>,
+  {
+    DB.get_image_id(uid)
+  }
+
+  // uid of a file id the platform handed out, e.g. to check whether it is
+  // cached, only known for ids put since this lookup exists
+  pub fn image_id_for<F>(&self, file_id: F) -> Option<Vec<u8>>
+  where
+    F: AsRef<[u8]>,
+  {
+    DB.get_uid_by_file_id(file_id)
+  }
+
+  pub fn put_image_id_batch<I, U, F>(&self, items: I) -> Result<()>
+  where
+    I: IntoIterator<Item = (U, F)>,
+    U: AsRef<[u8]>,
+    F: Into<IVec>,
+  {
+    DB.put_image_id_many(items)?;
+    Ok(())
+  }
+
+  // unlike put_image_id, doesn't write when uid is already known, e.g. on
+  // replays
+  pub fn put_image_id_if_absent<U, F>(&self, uid: U, file_id: F) -> bool
+  where
+    U: AsRef<[u8]>,
+    F: Into<IVec>,
+  {
+    DB.put_image_id_if_absent(uid, file_id)
+  }
+
   pub fn resolve_photo_url<F>(&self, f: F)
   where
     F: Fn(&(Vec<u8>, IVec)) -> BoxFuture<Result<ArcStr>> + Send + Sync + 'static,
   {
-    let h = Box::new(f);
-    self.photo_url_resolver.init(h);
+    self.update_photo_url_resolver(f);
+  }
+
+  // replaces the resolver atomically, e.g. after the platform session changed
+  pub fn update_photo_url_resolver<F>(&self, f: F)
+  where
+    F: Fn(&(Vec<u8>, IVec)) -> BoxFuture<Result<ArcStr>> + Send + Sync + 'static,
+  {
+    self.store_photo_url_resolver(Box::new(f));
+  }
+
+  pub(crate) fn store_photo_url_resolver(&self, h: Box<Handler>) {
+    self.photo_url_resolver.store(Some(Arc::new(h)));
+  }
+
+  // unlike wait_for, fires for every file created in the directory
+  pub fn on_file_created<F>(&self, f: F)
+  where
+    F: Fn(ArcStr, PathBuf) + Send + Sync + 'static,
+  {
+    self.update_on_file_created(f);
+  }
+
+  pub fn update_on_file_created<F>(&self, f: F)
+  where
+    F: Fn(ArcStr, PathBuf) + Send + Sync + 'static,
+  {
+    let callback: Box<FileCallback> = Box::new(f);
+    self.file_created_callback.store(Some(Arc::new(callback)));
   }
 
   pub async fn get_photo_url<T>(&self, uid: T) -> Option<ArcStr>
@@ -105,19 +437,93 @@ impl Res {
     T: AsRef<[u8]>,
   {
     let file_id = DB.get_image_id(&uid)?;
-    let handler = &*self.photo_url_resolver;
+    let handler = self.photo_url_resolver.load_full()?;
     handler(&(uid.as_ref().to_vec(), file_id))
       .await
       .unwrap()
       .some()
   }
+
+  pub async fn get_photo_url_cached<T>(&self, uid: T) -> Option<ArcStr>
+  where
+    T: AsRef<[u8]>,
+  {
+    if let Some(url) = DB.get_cached_url(&uid) {
+      return Some(url);
+    }
+    let url = self.get_photo_url(&uid).await?;
+    DB.put_cached_url(&uid, &url, PHOTO_URL_TTL)
+      .log_if_error("Failed to cache the photo url");
+    Some(url)
+  }
+}
+
+#[cfg(unix)]
+fn inode_of(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+  use std::os::unix::fs::MetadataExt;
+  Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode_of(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+  None
+}
+
+async fn move_files(from: &Path, to: &Path) -> std::io::Result<()> {
+  tokio::fs::create_dir_all(to.join("content")).await?;
+  let mut moved = Vec::new();
+  let result = async {
+    for dir in [PathBuf::new(), PathBuf::from("content")] {
+      let mut entries = tokio::fs::read_dir(from.join(&dir)).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+          continue;
+        }
+        // no writer is in flight here, tmp files are left over from a crash
+        if entry.file_name().to_string_lossy().ends_with(".tmp") {
+          continue;
+        }
+        let relative = dir.join(entry.file_name());
+        move_file(&from.join(&relative), &to.join(&relative)).await?;
+        moved.push(relative);
+      }
+    }
+    Ok::<(), std::io::Error>(())
+  }
+  .await;
+  if result.is_err() {
+    for relative in moved.iter().rev() {
+      if let Err(e) = move_file(&to.join(relative), &from.join(relative)).await {
+        error!("Failed to move back {:?}: {:?}", relative, e);
+      }
+    }
+  }
+  result
+}
+
+async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+  if tokio::fs::rename(from, to).await.is_err() {
+    // rename fails across mount points
+    tokio::fs::copy(from, to).await?;
+    tokio::fs::remove_file(from).await?;
+  }
+  Ok(())
 }
 
 #[cfg(test)]
 mod test {
+  use std::sync::{Mutex, MutexGuard};
+
+  // tests share the RES singleton and its directory
+  fn lock_res() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
   #[test]
   fn test() {
     use super::RES;
+    let _guard = lock_res();
     tokio::runtime::Builder::new_multi_thread()
       .worker_threads(8)
       .enable_all()
@@ -127,4 +533,106 @@ mod test {
         RES.init().await;
       });
   }
+
+  #[test]
+  fn init_test() {
+    use super::RES;
+    let _guard = lock_res();
+    tokio::runtime::Builder::new_multi_thread()
+      .enable_all()
+      .build()
+      .unwrap()
+      .block_on(async {
+        let dir = RES.init_test().await;
+        assert!(dir.path().join("content").exists());
+      });
+  }
+
+  #[test]
+  fn file_created_by_put_file() {
+    use super::RES;
+    use crate::cache::CACHE;
+    let _guard = lock_res();
+    tokio::runtime::Builder::new_multi_thread()
+      .enable_all()
+      .build()
+      .unwrap()
+      .block_on(async {
+        let dir = RES.init_test().await;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        RES.on_file_created(move |id, _| tx.lock().unwrap().send(id).unwrap());
+        let file = dir.path().join("outside");
+        tokio::fs::write(&file, b"data").await.unwrap();
+        let path = CACHE.put_file(&b"id".to_vec(), &file).await.unwrap();
+        let id = base64_url::encode(b"id");
+        assert_eq!(path.file_name().unwrap().to_string_lossy(), id);
+        let created = rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(created, id);
+        RES.update_on_file_created(|_, _| {});
+      });
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn directory_size_hard_link() {
+    use super::RES;
+    let _guard = lock_res();
+    tokio::runtime::Builder::new_multi_thread()
+      .enable_all()
+      .build()
+      .unwrap()
+      .block_on(async {
+        let dir = RES.init_test().await;
+        let content = dir.path().join("content").join("hash");
+        tokio::fs::write(&content, [0u8; 100]).await.unwrap();
+        tokio::fs::hard_link(&content, dir.path().join("id"))
+          .await
+          .unwrap();
+        assert_eq!(RES.directory_size().await.unwrap(), 100);
+      });
+  }
+
+  #[test]
+  fn set_directory_waits_for_writers() {
+    use super::RES;
+    use arcstr::ArcStr;
+    use tokio::sync::oneshot;
+    let _guard = lock_res();
+    tokio::runtime::Builder::new_multi_thread()
+      .enable_all()
+      .build()
+      .unwrap()
+      .block_on(async {
+        let dir = RES.init_test().await;
+        let new_dir = tempfile::Builder::new()
+          .prefix("mesagisto-test")
+          .tempdir()
+          .unwrap();
+        let (started_tx, started_rx) = oneshot::channel();
+        let (finish_tx, finish_rx) = oneshot::channel::<()>();
+        let writer = tokio::spawn(async move {
+          RES
+            .with_temp_file(&ArcStr::from("in-flight"), move |tmp_path| {
+              Box::pin(async move {
+                tokio::fs::write(tmp_path, b"data").await?;
+                started_tx.send(()).unwrap();
+                finish_rx.await?;
+                Ok(())
+              })
+            })
+            .await
+        });
+        started_rx.await.unwrap();
+        let moving = tokio::spawn(RES.set_directory(new_dir.path().to_path_buf()));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(RES.directory_path().as_path(), dir.path());
+        finish_tx.send(()).unwrap();
+        writer.await.unwrap().unwrap();
+        moving.await.unwrap().unwrap();
+        assert!(new_dir.path().join("in-flight").exists());
+        assert!(!dir.path().join("in-flight").exists());
+        assert!(!dir.path().join("in-flight.tmp").exists());
+      });
+  }
 }
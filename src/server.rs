@@ -1,33 +1,386 @@
-use std::future::Future;
+use std::{
+  collections::HashMap,
+  future::Future,
+  sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, RwLock,
+  },
+  time::{Duration, Instant},
+};
 
 use arcstr::ArcStr;
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::{eyre, Report, Result};
 use dashmap::DashMap;
-use futures::StreamExt;
+use either::Either;
+use futures::{future::BoxFuture, Stream, StreamExt};
 use lateinit::LateInit;
 use nats::{header::HeaderMap, Client, HeaderValue};
 use rand::prelude::random;
-use tokio::task::JoinHandle;
+use tokio::{
+  sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+  task::JoinHandle,
+};
 use tracing::{debug, info, trace};
 
 use crate::{
   cipher::CIPHER,
-  data::{events::Event, Packet},
+  data::{
+    events::{Event, RelayInfo, SUPPORTED_EVENTS},
+    message::Message,
+    Packet,
+  },
+  error::{DataError, ServerError},
   EitherExt, LogResultExt,
 };
 
+type Interceptor = dyn Fn(Packet) -> BoxFuture<'static, Result<Packet>> + Send + Sync + 'static;
+type ErrorHandler = dyn Fn(&Report) + Send + Sync + 'static;
+#[cfg(feature = "otel")]
+type Propagator = dyn opentelemetry::propagation::TextMapPropagator + Send + Sync + 'static;
+
+// how long the relay info of an address is reused before asking again
+const ADDRESS_INFO_TTL: Duration = Duration::from_secs(60);
+// largest packet this lib accepts, reported in InfoResponse
+const MAX_PACKET_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerMetrics {
+  pub requests_sent: u64,
+  pub responses_received: u64,
+  pub request_errors: u64,
+  pub total_bytes_sent: u64,
+  pub total_bytes_received: u64,
+  pub avg_latency_ms: u64,
+  // Priority::High requests awaiting their response
+  pub high_priority_inflight: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+  requests_sent: AtomicU64,
+  responses_received: AtomicU64,
+  request_errors: AtomicU64,
+  bytes_sent: AtomicU64,
+  bytes_received: AtomicU64,
+  // latency summed over all responses
+  latency_ms: AtomicU64,
+  high_priority_inflight: AtomicU64,
+}
+
+// counts a request as in flight until dropped, so cancelled ones are released
+struct InflightGuard<'a>(&'a AtomicU64);
+impl<'a> InflightGuard<'a> {
+  fn new(counter: &'a AtomicU64) -> Self {
+    counter.fetch_add(1, Ordering::Relaxed);
+    Self(counter)
+  }
+}
+impl Drop for InflightGuard<'_> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+// a request past Server::gate, ready to be sent
+struct RequestGate<'a> {
+  address: ArcStr,
+  payload: bytes::Bytes,
+  headers: HeaderMap,
+  pass: Option<BreakerPass>,
+  _permit: Option<OwnedSemaphorePermit>,
+  _inflight: Option<InflightGuard<'a>>,
+  start: Instant,
+}
+
+// retries of Server::request on transport failures, the delay doubles after
+// every attempt
+#[derive(Debug, Clone, Copy)]
+pub struct ServerRetryPolicy {
+  pub max_attempts: u8,
+  pub base_delay_ms: u64,
+  // an attempt without reply by then fails with ServerError::Timeout
+  pub attempt_timeout_ms: u64,
+}
+impl Default for ServerRetryPolicy {
+  fn default() -> Self {
+    // no retry
+    Self {
+      max_attempts: 1,
+      base_delay_ms: 100,
+      attempt_timeout_ms: 10_000,
+    }
+  }
+}
+
+// how requests to an address authenticate, see Server::authenticate
+#[derive(Clone)]
+pub enum Credential {
+  BearerToken(ArcStr),
+  BasicAuth { user: ArcStr, pass: ArcStr },
+  // signs the packet, see Packet::sign
+  HmacKey(Vec<u8>),
+}
+
+// see Server::request_with_priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+  #[default]
+  Normal,
+  High,
+}
+
+// how requests pick a connection of the address pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolStrategy {
+  #[default]
+  RoundRobin,
+  // the relay with the lowest rolling average latency
+  LeastLatency,
+}
+
+// see Server::set_circuit_breaker
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerConfig {
+  // consecutive failures opening the circuit
+  pub failure_threshold: u8,
+  // how long requests fail fast before a probe is let through
+  pub open_duration: Duration,
+  // delay before the next probe when one failed
+  pub half_open_probe_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+  Closed { failures: u8 },
+  Open { until: Instant },
+  // a probe is in flight, other requests still fail fast
+  HalfOpen,
+}
+
+struct CircuitBreaker {
+  config: CircuitBreakerConfig,
+  state: std::sync::Mutex<BreakerState>,
+}
+impl CircuitBreaker {
+  fn new(config: CircuitBreakerConfig) -> Self {
+    Self {
+      config,
+      state: std::sync::Mutex::new(BreakerState::Closed { failures: 0 }),
+    }
+  }
+
+  fn try_pass(&self, now: Instant) -> Result<(), ServerError> {
+    let mut state = self.state.lock().unwrap();
+    match *state {
+      BreakerState::Closed { .. } => Ok(()),
+      BreakerState::Open { until } if now >= until => {
+        *state = BreakerState::HalfOpen;
+        Ok(())
+      }
+      BreakerState::Open { .. } | BreakerState::HalfOpen => Err(ServerError::CircuitOpen),
+    }
+  }
+
+  fn on_success(&self) {
+    *self.state.lock().unwrap() = BreakerState::Closed { failures: 0 };
+  }
+
+  fn on_failure(&self, now: Instant) {
+    let mut state = self.state.lock().unwrap();
+    *state = match *state {
+      BreakerState::Closed { failures } if failures + 1 >= self.config.failure_threshold => {
+        BreakerState::Open {
+          until: now + self.config.open_duration,
+        }
+      }
+      BreakerState::Closed { failures } => BreakerState::Closed {
+        failures: failures + 1,
+      },
+      BreakerState::HalfOpen => BreakerState::Open {
+        until: now + self.config.half_open_probe_interval,
+      },
+      open => open,
+    };
+  }
+}
+
+// a request let through by a breaker, counted as failed when dropped before
+// finish, e.g. when the caller timed out
+struct BreakerPass {
+  breaker: Arc<CircuitBreaker>,
+  finished: bool,
+}
+impl BreakerPass {
+  fn new(breaker: Arc<CircuitBreaker>) -> Result<Self, ServerError> {
+    breaker.try_pass(Instant::now())?;
+    Ok(Self {
+      breaker,
+      finished: false,
+    })
+  }
+
+  fn finish(mut self, success: bool) {
+    self.finished = true;
+    if success {
+      self.breaker.on_success();
+    } else {
+      self.breaker.on_failure(Instant::now());
+    }
+  }
+}
+impl Drop for BreakerPass {
+  fn drop(&mut self) {
+    if !self.finished {
+      self.breaker.on_failure(Instant::now());
+    }
+  }
+}
+
+struct PoolMember {
+  address: ArcStr,
+  client: Client,
+  // rolling average, 0 until the first response
+  latency_ms: AtomicU64,
+}
+impl PoolMember {
+  fn record_latency(&self, sample_ms: u64) {
+    let average = self.latency_ms.load(Ordering::Relaxed);
+    let average = if average == 0 {
+      sample_ms
+    } else {
+      (average * 7 + sample_ms) / 8
+    };
+    self.latency_ms.store(average.max(1), Ordering::Relaxed);
+  }
+}
+
+// client-side filter of Server::subscribe_to_channel, empty lists accept
+// everything
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionFilter {
+  channels: Vec<ArcStr>,
+  event_types: Vec<ArcStr>,
+}
+impl SubscriptionFilter {
+  // events about no channel in particular pass the channel filter, messages
+  // have the event type "message"
+  pub fn accepts(&self, packet: &Either<Message, Event>) -> bool {
+    let (kind, channel) = match packet {
+      Either::Left(message) => ("message", Some(&message.channel)),
+      Either::Right(event) => (event.kind(), event.channel()),
+    };
+    let kind_accepted = self.event_types.is_empty() || self.event_types.iter().any(|t| t == kind);
+    let channel_accepted = match channel {
+      Some(channel) => self.channels.is_empty() || self.channels.contains(channel),
+      None => true,
+    };
+    kind_accepted && channel_accepted
+  }
+}
+
+pub struct SubscriptionBuilder {
+  target: ArcStr,
+  address: ArcStr,
+  filter: SubscriptionFilter,
+}
+impl SubscriptionBuilder {
+  pub fn filter_channel(mut self, channel: ArcStr) -> Self {
+    self.filter.channels.push(channel);
+    self
+  }
+
+  pub fn filter_event_types(mut self, types: Vec<&str>) -> Self {
+    self
+      .filter
+      .event_types
+      .extend(types.into_iter().map(ArcStr::from));
+    self
+  }
+
+  // like Server::recv, but handler only sees packets passing the filter,
+  // undecodable packets are handed over as well
+  pub async fn recv<H, Fut>(self, handler: H) -> Result<()>
+  where
+    H: Fn(nats::Message, ArcStr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+  {
+    let filter = self.filter;
+    SERVER
+      .recv(self.target, &self.address, move |next, target| {
+        let accepted = match Packet::from_cbor(&next.payload) {
+          Ok(packet) => filter.accepts(&packet),
+          Err(_) => true,
+        };
+        let fut = accepted.then(|| handler(next, target));
+        async move {
+          match fut {
+            Some(fut) => fut.await,
+            None => Ok(()),
+          }
+        }
+      })
+      .await
+  }
+}
+
+// a persistent two-way packet stream on one address, the NATS connection below
+// is kept alive by its own PING/PONG heartbeats
+pub struct Channel {
+  pub tx: mpsc::Sender<Packet>,
+  pub rx: mpsc::Receiver<Packet>,
+  task: JoinHandle<()>,
+}
+impl Channel {
+  // stops the channel and waits for the NATS server to acknowledge everything
+  // sent so far
+  pub async fn close(self) -> Result<()> {
+    let Channel { tx, rx, task } = self;
+    drop(tx);
+    drop(rx);
+    task.await?;
+    SERVER.client.flush().await.map_err(|e| eyre!(e))?;
+    Ok(())
+  }
+}
+
 #[derive(Singleton, Default)]
 pub struct Server {
   pub client: LateInit<Client>,
   pub address: LateInit<ArcStr>,
   pub cid: LateInit<u64>,
   pub lib_header: LateInit<HeaderMap>,
+  pub batch_limit: LateInit<usize>,
+  // of this adapter, sent along with image events
+  pub platform: LateInit<ArcStr>,
   pub endpoint: DashMap<ArcStr, JoinHandle<()>>,
   pub unique_address: DashMap<ArcStr, ArcStr>,
+  // routing table of channels announced by remote libs, with their metadata
+  pub channels: DashMap<ArcStr, HashMap<ArcStr, ArcStr>>,
+  address_alias: DashMap<ArcStr, ArcStr>,
+  address_info: DashMap<ArcStr, (Instant, RelayInfo)>,
+  interceptors: RwLock<Vec<Arc<Interceptor>>>,
+  error_handler: RwLock<Option<Arc<ErrorHandler>>>,
+  // drop received packets older than their ttl
+  enforce_ttl: AtomicBool,
+  retry_policy: RwLock<ServerRetryPolicy>,
+  credentials: DashMap<ArcStr, Credential>,
+  breakers: DashMap<ArcStr, Arc<CircuitBreaker>>,
+  #[cfg(feature = "otel")]
+  propagator: RwLock<Option<Arc<Propagator>>>,
+  // relays requests are spread over, the client above is used when empty
+  pool: RwLock<Vec<Arc<PoolMember>>>,
+  pool_strategy: RwLock<PoolStrategy>,
+  pool_cursor: AtomicUsize,
+  // limits concurrent requests, see set_max_inflight
+  inflight: RwLock<Option<Arc<Semaphore>>>,
+  backpressure_wait: AtomicBool,
+  counters: Counters,
 }
 impl Server {
-  pub async fn init(&self, address: &ArcStr) -> Result<()> {
+  pub async fn init(&self, address: &ArcStr, batch_limit: usize, platform: ArcStr) -> Result<()> {
     self.address.init(address.to_owned());
+    self.batch_limit.init(batch_limit);
+    self.platform.init(platform);
     let client = {
       info!("{}", t!("log.connecting", address = address));
       let nc = nats::connect(address.to_string()).await?;
@@ -62,8 +415,206 @@ impl Server {
     Ok(header)
   }
 
+  // interceptors run in registration order on every outgoing packet
+  pub fn add_interceptor<F>(&self, f: F)
+  where
+    F: Fn(Packet) -> BoxFuture<'static, Result<Packet>> + Send + Sync + 'static,
+  {
+    self.interceptors.write().unwrap().push(Arc::new(f));
+  }
+
+  pub fn clear_interceptors(&self) {
+    self.interceptors.write().unwrap().clear();
+  }
+
+  async fn intercept(&self, mut packet: Packet) -> Result<Packet> {
+    let interceptors = self.interceptors.read().unwrap().clone();
+    for interceptor in interceptors {
+      packet = interceptor(packet).await?;
+    }
+    Ok(packet)
+  }
+
+  // called for every error raised in background tasks, e.g. to report them to
+  // an external error tracker
+  pub fn on_error<F>(&self, f: F)
+  where
+    F: Fn(&Report) + Send + Sync + 'static,
+  {
+    *self.error_handler.write().unwrap() = Some(Arc::new(f));
+  }
+
+  fn report_error(&self, error: &Report) {
+    let handler = self.error_handler.read().unwrap().clone();
+    if let Some(handler) = handler {
+      handler(error);
+    }
+  }
+
+  // trace context of requests is injected into their headers, and extracted
+  // from received packets for the handler of Server::recv
+  #[cfg(feature = "otel")]
+  pub fn set_otel_propagator<P>(&self, propagator: P)
+  where
+    P: opentelemetry::propagation::TextMapPropagator + Send + Sync + 'static,
+  {
+    *self.propagator.write().unwrap() = Some(Arc::new(propagator));
+  }
+
+  #[cfg(feature = "otel")]
+  fn inject_trace_context(&self, headers: &mut HeaderMap) {
+    let propagator = self.propagator.read().unwrap().clone();
+    if let Some(propagator) = propagator {
+      propagator.inject_context(
+        &opentelemetry::Context::current(),
+        &mut opentelemetry_http::HeaderInjector(headers),
+      );
+    }
+  }
+
+  #[cfg(feature = "otel")]
+  fn extract_trace_context(&self, headers: Option<&HeaderMap>) -> opentelemetry::Context {
+    let propagator = self.propagator.read().unwrap().clone();
+    match (propagator, headers) {
+      (Some(propagator), Some(headers)) => {
+        propagator.extract(&opentelemetry_http::HeaderExtractor(headers))
+      }
+      _ => opentelemetry::Context::current(),
+    }
+  }
+
+  // applies cred to every request to address, bearer and basic credentials
+  // as the authorization header
+  pub fn authenticate(&self, address: &ArcStr, cred: Credential) {
+    self.credentials.insert(self.resolve_alias(address), cred);
+  }
+
+  fn apply_credential(
+    &self,
+    address: &ArcStr,
+    content: &mut Packet,
+    headers: &mut HeaderMap,
+  ) -> Result<()> {
+    let credential = match self.credentials.get(&self.resolve_alias(address)) {
+      Some(credential) => credential.clone(),
+      None => return Ok(()),
+    };
+    let authorization = match credential {
+      Credential::BearerToken(token) => format!("Bearer {}", token),
+      Credential::BasicAuth { user, pass } => {
+        format!("Basic {}", base64::encode(format!("{}:{}", user, pass)))
+      }
+      Credential::HmacKey(key) => {
+        content.sign(&key);
+        return Ok(());
+      }
+    };
+    let mut value = HeaderValue::from_str(&authorization)?;
+    value.set_sensitive(true);
+    headers.insert("authorization", value);
+    Ok(())
+  }
+
+  // connects to every relay in addresses and spreads requests over them,
+  // subscriptions stay on the connection made in init
+  pub async fn set_address_pool(&self, addresses: Vec<ArcStr>) -> Result<()> {
+    let mut pool = Vec::with_capacity(addresses.len());
+    for address in addresses {
+      info!("{}", t!("log.connecting", address = &address));
+      let client = nats::connect(address.to_string()).await?;
+      pool.push(Arc::new(PoolMember {
+        address,
+        client,
+        latency_ms: AtomicU64::new(0),
+      }));
+    }
+    *self.pool.write().unwrap() = pool;
+    Ok(())
+  }
+
+  pub fn set_pool_strategy(&self, strategy: PoolStrategy) {
+    *self.pool_strategy.write().unwrap() = strategy;
+  }
+
+  pub fn current_pool(&self) -> Vec<ArcStr> {
+    let pool = self.pool.read().unwrap();
+    pool.iter().map(|member| member.address.clone()).collect()
+  }
+
+  fn pick_pool_member(&self) -> Option<Arc<PoolMember>> {
+    let pool = self.pool.read().unwrap();
+    if pool.is_empty() {
+      return None;
+    }
+    let member = match *self.pool_strategy.read().unwrap() {
+      PoolStrategy::RoundRobin => {
+        let cursor = self.pool_cursor.fetch_add(1, Ordering::Relaxed);
+        &pool[cursor % pool.len()]
+      }
+      PoolStrategy::LeastLatency => pool
+        .iter()
+        .min_by_key(|member| member.latency_ms.load(Ordering::Relaxed))
+        .unwrap(),
+    };
+    Some(member.clone())
+  }
+
+  // after cfg.failure_threshold consecutive failed requests to address, further
+  // requests fail with ServerError::CircuitOpen until one probe succeeds
+  pub fn set_circuit_breaker(&self, address: &ArcStr, cfg: CircuitBreakerConfig) {
+    self
+      .breakers
+      .insert(self.resolve_alias(address), Arc::new(CircuitBreaker::new(cfg)));
+  }
+
+  pub fn set_retry_policy(&self, policy: ServerRetryPolicy) {
+    *self.retry_policy.write().unwrap() = policy;
+  }
+
+  // requests beyond n fail with ServerError::Backpressure, or wait for a slot
+  // with set_backpressure_wait(true)
+  pub fn set_max_inflight(&self, n: usize) {
+    *self.inflight.write().unwrap() = Some(Arc::new(Semaphore::new(n)));
+  }
+
+  pub fn set_backpressure_wait(&self, wait: bool) {
+    self.backpressure_wait.store(wait, Ordering::Relaxed);
+  }
+
+  pub fn set_enforce_ttl(&self, enforce: bool) {
+    self.enforce_ttl.store(enforce, Ordering::Relaxed);
+  }
+
+  pub fn metrics(&self) -> ServerMetrics {
+    let counters = &self.counters;
+    let responses_received = counters.responses_received.load(Ordering::Relaxed);
+    let latency_ms = counters.latency_ms.load(Ordering::Relaxed);
+    ServerMetrics {
+      requests_sent: counters.requests_sent.load(Ordering::Relaxed),
+      responses_received,
+      request_errors: counters.request_errors.load(Ordering::Relaxed),
+      total_bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+      total_bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+      avg_latency_ms: latency_ms.checked_div(responses_received).unwrap_or(0),
+      high_priority_inflight: counters.high_priority_inflight.load(Ordering::Relaxed),
+    }
+  }
+
+  pub fn add_address_alias(&self, alias: ArcStr, address: ArcStr) {
+    self.address_alias.insert(alias, address);
+  }
+
+  // the address behind alias_or_address, or itself when it isn't an alias
+  pub fn resolve_alias(&self, alias_or_address: &ArcStr) -> ArcStr {
+    match self.address_alias.get(alias_or_address) {
+      Some(address) => address.clone(),
+      None => alias_or_address.clone(),
+    }
+  }
+
   pub fn unique_address(&self, address: &ArcStr) -> ArcStr {
     use sha2::{Digest, Sha256};
+    let address = &self.resolve_alias(address);
     let entry = self.unique_address.entry(address.clone());
     entry
       .or_insert_with(|| {
@@ -84,7 +635,7 @@ impl Server {
     headers: Option<HeaderMap>,
   ) -> Result<()> {
     let unique_address = self.unique_address(address);
-    let payload = content.to_cbor()?;
+    let payload = self.intercept(content).await?.to_cbor()?;
     let headers = match headers {
       Some(headers) => headers,
       None => {
@@ -97,6 +648,10 @@ impl Server {
       }
     };
 
+    self
+      .counters
+      .bytes_sent
+      .fetch_add(payload.len() as u64, Ordering::Relaxed);
     self
       .client
       .publish_with_headers(
@@ -109,6 +664,119 @@ impl Server {
     Ok(())
   }
 
+  // sends messages as MessageBatch packets of at most the configured
+  // batch_limit messages each
+  pub async fn send_batch(
+    &self,
+    target: &ArcStr,
+    address: &ArcStr,
+    messages: Vec<Message>,
+    headers: Option<HeaderMap>,
+  ) -> Result<()> {
+    for packet in Packet::from_batch(messages, *self.batch_limit)? {
+      self.send(target, address, packet, headers.clone()).await?;
+    }
+    Ok(())
+  }
+
+  // sends event without building the packet by hand, request events wait for
+  // the response, other events are fire-and-forget and yield None
+  pub async fn send_event<E>(
+    &self,
+    target: &ArcStr,
+    address: &ArcStr,
+    event: E,
+  ) -> Result<Option<Event>>
+  where
+    E: Into<Event>,
+  {
+    let event: Event = event.into();
+    let is_request = event.is_request();
+    let packet = Packet::from(event.to_right())?;
+    if !is_request {
+      self.send(target, address, packet, None).await?;
+      return Ok(None);
+    }
+    let reply = self.request(address, packet, self.new_lib_header()?).await?;
+    match Packet::from_cbor(&reply.payload)? {
+      Either::Right(event) => Ok(Some(event)),
+      Either::Left(_) => Err(DataError::UnexpectedResponse("message".into()).into()),
+    }
+  }
+
+  pub async fn connect(&self, address: &ArcStr) -> Result<Channel> {
+    let address = self.unique_address(address);
+    let mut sub = self
+      .client
+      .subscribe(address.to_string())
+      .await
+      .map_err(|e| eyre!(e))?;
+    let (tx, mut outgoing) = mpsc::channel::<Packet>(32);
+    let (incoming, rx) = mpsc::channel::<Packet>(32);
+    // the task spawned below should use singleton,because it's "outside" of our
+    // logic
+    let task = tokio::spawn(async move {
+      loop {
+        tokio::select! {
+          packet = outgoing.recv() => match packet {
+            Some(packet) => {
+              let result = SERVER.publish_lib_packet(&address, packet).await;
+              if let Err(e) = &result {
+                SERVER.report_error(e);
+              }
+              result.log_if_error(&t!("log.log-callback-err"));
+            }
+            None => break,
+          },
+          next = sub.next() => match next {
+            Some(next) => {
+              let from_remote = match next.headers.as_ref() {
+                Some(meta) => meta.is_remote_lib(*SERVER.cid),
+                None => false,
+              };
+              if !from_remote {
+                continue;
+              }
+              match serde_cbor::from_slice::<Packet>(&next.payload) {
+                Ok(packet) => {
+                  let _ = incoming.send(packet).await;
+                }
+                Err(e) => debug!("Drop undecodable channel packet: {}", e),
+              }
+            }
+            None => break,
+          },
+        }
+      }
+      let _ = sub.unsubscribe().await;
+    });
+    Ok(Channel { tx, rx, task })
+  }
+
+  async fn publish_lib_packet(&self, address: &ArcStr, content: Packet) -> Result<()> {
+    let payload = self.intercept(content).await?.to_cbor()?;
+    self
+      .client
+      .publish_with_headers(
+        address.to_string(),
+        self.new_lib_header()?,
+        bytes::Bytes::from(payload),
+      )
+      .await
+      .map_err(|e| eyre!(e))?;
+    Ok(())
+  }
+
+  // the relay doesn't filter, so the filters of the returned builder apply on
+  // this side
+  pub fn subscribe_to_channel(&self, target: ArcStr, address: ArcStr) -> SubscriptionBuilder {
+    SubscriptionBuilder {
+      target,
+      address,
+      filter: SubscriptionFilter::default(),
+    }
+  }
+
   pub async fn recv<H, Fut>(&self, target: ArcStr, address: &ArcStr, handler: H) -> Result<()>
   where
     H: Fn(nats::Message, ArcStr) -> Fut + Send + Sync + 'static,
@@ -133,10 +801,21 @@ impl Server {
     // logic
     let join = tokio::spawn(async move {
       while let Some(next) = sub.next().await {
-        handle_incoming(next, &target, &handler)
-          .await
-          // .log_if_error("Err when handing incoming nats message");
-          .log_if_error(&t!("log.log-callback-err"));
+        SERVER
+          .counters
+          .bytes_received
+          .fetch_add(next.payload.len() as u64, Ordering::Relaxed);
+        #[cfg(feature = "otel")]
+        let cx = SERVER.extract_trace_context(next.headers.as_ref());
+        let result = handle_incoming(next, &target, &handler);
+        #[cfg(feature = "otel")]
+        let result = opentelemetry::trace::FutureExt::with_context(result, cx);
+        let result = result.await;
+        if let Err(e) = &result {
+          SERVER.report_error(e);
+        }
+        // .log_if_error("Err when handing incoming nats message");
+        result.log_if_error(&t!("log.log-callback-err"));
       }
       async fn handle_incoming<H, Fut>(
         next: nats::Message,
@@ -161,7 +840,46 @@ impl Server {
         };
         if let Some(next) = next {
           trace!("{}", t!("log.recv-msg", target = &target));
-          handler(next, target.clone()).await?;
+          let enforce_ttl = SERVER.enforce_ttl.load(Ordering::Relaxed);
+          // deserialized once, for the ttl and for unpacking batches
+          let packet = match serde_cbor::from_slice::<Packet>(&next.payload) {
+            Ok(packet) => Some(packet),
+            Err(e) if enforce_ttl => return Err(e.into()),
+            Err(_) => None,
+          };
+          if let Some(packet) = packet.as_ref().filter(|_| enforce_ttl) {
+            if packet.is_expired() {
+              return Err(
+                ServerError::PacketExpired {
+                  age_secs: packet.age().as_secs(),
+                  ttl: packet.ttl.unwrap_or_default(),
+                }
+                .into(),
+              );
+            }
+          }
+          // only events are decrypted here, messages are left to the handler
+          let event = packet
+            .filter(|packet| packet.r#type == "event")
+            .map(Packet::into_event);
+          match event {
+            Some(Ok(Event::MessageBatch { messages })) => {
+              if messages.len() > *SERVER.batch_limit {
+                return Err(eyre!(
+                  "Message batch of {} exceeds the limit {}",
+                  messages.len(),
+                  *SERVER.batch_limit
+                ));
+              }
+              // dispatch each message of the batch as if it came alone
+              for message in messages {
+                let mut single = next.clone();
+                single.payload = Packet::from(message.to_left())?.to_cbor()?.into();
+                handler(single, target.clone()).await?;
+              }
+            }
+            _ => handler(next, target.clone()).await?,
+          }
         };
         Ok(())
       }
@@ -176,30 +894,307 @@ impl Server {
     content: Packet,
     headers: HeaderMap,
   ) -> Result<nats::Message> {
+    self
+      .request_with_priority(address, content, headers, Priority::Normal)
+      .await
+  }
+
+  // Priority::High requests, e.g. control messages, skip the in-flight limit
+  // of set_max_inflight
+  pub async fn request_with_priority(
+    &self,
+    address: &ArcStr,
+    content: Packet,
+    headers: HeaderMap,
+    priority: Priority,
+  ) -> Result<nats::Message> {
+    let gate = self.gate(address, content, headers, priority).await?;
+    let result = self
+      .retry_request(|client| {
+        self.try_request(client, &gate.address, gate.payload.clone(), gate.headers.clone())
+      })
+      .await;
+    let reply_len = result.as_ref().ok().map(|reply| reply.payload.len());
+    self.settle(gate.pass, gate.start, reply_len);
+    result.map_err(Into::into)
+  }
+
+  // what every request goes through before it is sent: interceptors,
+  // validation, credentials, the circuit breaker and the in-flight limit
+  async fn gate(
+    &self,
+    address: &ArcStr,
+    content: Packet,
+    mut headers: HeaderMap,
+    priority: Priority,
+  ) -> Result<RequestGate<'_>> {
+    let mut content = self.intercept(content).await?;
+    content.validate()?;
+    self.apply_credential(address, &mut content, &mut headers)?;
+    #[cfg(feature = "otel")]
+    self.inject_trace_context(&mut headers);
+    let breaker = self
+      .breakers
+      .get(&self.resolve_alias(address))
+      .map(|b| b.clone());
     let address = self.unique_address(address);
+    let semaphore = match priority {
+      Priority::Normal => self.inflight.read().unwrap().clone(),
+      Priority::High => None,
+    };
+    let permit = match semaphore {
+      Some(semaphore) if self.backpressure_wait.load(Ordering::Relaxed) => {
+        Some(semaphore.acquire_owned().await?)
+      }
+      Some(semaphore) => Some(
+        semaphore
+          .try_acquire_owned()
+          .map_err(|_| ServerError::Backpressure)?,
+      ),
+      None => None,
+    };
     trace!("{}", t!("log.send-request"));
-    let inbox = self.client.new_inbox();
-    let mut sub = self
-      .client
+    let payload = bytes::Bytes::from(content.to_cbor()?);
+    // taken last, so a request failing before it is sent leaves the breaker
+    // alone
+    let pass = breaker.map(BreakerPass::new).transpose()?;
+    let counters = &self.counters;
+    counters.requests_sent.fetch_add(1, Ordering::Relaxed);
+    counters
+      .bytes_sent
+      .fetch_add(payload.len() as u64, Ordering::Relaxed);
+    let inflight = match priority {
+      Priority::High => Some(InflightGuard::new(&counters.high_priority_inflight)),
+      Priority::Normal => None,
+    };
+    Ok(RequestGate {
+      address,
+      payload,
+      headers,
+      pass,
+      _permit: permit,
+      _inflight: inflight,
+      start: Instant::now(),
+    })
+  }
+
+  // reports the outcome of a gated request to its breaker and the counters,
+  // reply_len is None when it failed
+  fn settle(&self, pass: Option<BreakerPass>, start: Instant, reply_len: Option<usize>) {
+    if let Some(pass) = pass {
+      pass.finish(reply_len.is_some());
+    }
+    let counters = &self.counters;
+    match reply_len {
+      Some(len) => {
+        counters.responses_received.fetch_add(1, Ordering::Relaxed);
+        counters
+          .bytes_received
+          .fetch_add(len as u64, Ordering::Relaxed);
+        counters
+          .latency_ms
+          .fetch_add(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+      }
+      None => {
+        counters.request_errors.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+  }
+
+  // only transient errors are retried, see ServerError::is_transient
+  async fn retry_request<T, F, Fut>(&self, attempt_with: F) -> Result<T, ServerError>
+  where
+    F: Fn(Client) -> Fut,
+    Fut: Future<Output = Result<T, ServerError>>,
+  {
+    let policy = *self.retry_policy.read().unwrap();
+    let timeout = Duration::from_millis(policy.attempt_timeout_ms);
+    let mut delay = Duration::from_millis(policy.base_delay_ms);
+    let mut attempt = 1;
+    loop {
+      // every attempt picks again, so a retry may reach another relay
+      let member = self.pick_pool_member();
+      let client = match &member {
+        Some(member) => member.client.clone(),
+        None => (*self.client).clone(),
+      };
+      let start = Instant::now();
+      let attempt_result = tokio::time::timeout(timeout, attempt_with(client))
+        .await
+        .unwrap_or(Err(ServerError::Timeout(timeout)));
+      match attempt_result {
+        Ok(reply) => {
+          if let Some(member) = member {
+            member.record_latency(start.elapsed().as_millis() as u64);
+          }
+          return Ok(reply);
+        }
+        Err(e) if e.is_transient() && attempt < policy.max_attempts => {
+          debug!("Request attempt {} failed: {}, retrying", attempt, e);
+          tokio::time::sleep(delay).await;
+          delay *= 2;
+          attempt += 1;
+        }
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  async fn try_request(
+    &self,
+    client: Client,
+    address: &ArcStr,
+    payload: bytes::Bytes,
+    headers: HeaderMap,
+  ) -> Result<nats::Message, ServerError> {
+    let (mut sub, reply) = self.try_open_stream(client, address, payload, headers).await?;
+    if let Err(e) = sub.unsubscribe().await {
+      debug!("Failed to unsubscribe the reply inbox: {}", e);
+    }
+    Ok(reply)
+  }
+
+  // sends the request and waits for its first reply, the subscription stays
+  // open for the replies after it
+  async fn try_open_stream(
+    &self,
+    client: Client,
+    address: &ArcStr,
+    payload: bytes::Bytes,
+    headers: HeaderMap,
+  ) -> Result<(nats::Subscriber, nats::Message), ServerError> {
+    let transport = |e: &dyn std::fmt::Display| ServerError::Transport(e.to_string());
+    let inbox = client.new_inbox();
+    let mut sub = client
       .subscribe(inbox.clone())
       .await
-      .map_err(|e| eyre!(e))?;
-    self
-      .client
-      .publish_with_reply_and_headers(
-        address.to_string(),
-        inbox,
-        headers,
-        bytes::Bytes::from(content.to_cbor()?),
-      )
-      .await
-      .map_err(|e| eyre!(e))?;
-    let reply = sub
-      .next()
+      .map_err(|e| transport(&e))?;
+    client
+      .publish_with_reply_and_headers(address.to_string(), inbox, headers, payload)
       .await
-      .expect("the subscription has been unsubscribed or the connection is closed.");
-    sub.unsubscribe().await?;
-    Ok(reply)
+      .map_err(|e| transport(&e))?;
+    let reply = sub.next().await.ok_or(ServerError::ConnectionClosed)?;
+    Ok((sub, reply))
+  }
+
+  // for requests answered with several packets, the stream ends with the
+  // EndOfStream event, which isn't yielded. it is gated and retried like
+  // request until the first packet arrives, and holds its in-flight permit
+  // until it ends
+  pub async fn request_stream(
+    &self,
+    address: &ArcStr,
+    content: Packet,
+    headers: HeaderMap,
+  ) -> Result<impl Stream<Item = Result<Packet>> + '_> {
+    let gate = self
+      .gate(address, content, headers, Priority::Normal)
+      .await?;
+    let opened = self
+      .retry_request(|client| {
+        self.try_open_stream(client, &gate.address, gate.payload.clone(), gate.headers.clone())
+      })
+      .await;
+    let first_len = opened.as_ref().ok().map(|(_, first)| first.payload.len());
+    let RequestGate {
+      pass,
+      start,
+      _permit: permit,
+      _inflight: inflight,
+      ..
+    } = gate;
+    self.settle(pass, start, first_len);
+    let (sub, first) = opened?;
+    // the permit and the in-flight guard go with the stream, released once
+    // it ends or is dropped
+    let held = (permit, inflight);
+    Ok(futures::stream::unfold(
+      (Some(sub), Some(first), held),
+      |(sub, first, held)| async move {
+        let mut sub = sub?;
+        let next = match first {
+          Some(first) => first,
+          None => match sub.next().await {
+            Some(next) => {
+              SERVER
+                .counters
+                .bytes_received
+                .fetch_add(next.payload.len() as u64, Ordering::Relaxed);
+              next
+            }
+            None => {
+              let closed = eyre!("Stream closed before EndOfStream");
+              return Some((Err(closed), (None, None, held)));
+            }
+          },
+        };
+        let packet = match serde_cbor::from_slice::<Packet>(&next.payload) {
+          Ok(packet) => packet,
+          Err(e) => return Some((Err(e.into()), (Some(sub), None, held))),
+        };
+        if let Ok(Event::EndOfStream {}) = packet.as_event() {
+          let _ = sub.unsubscribe().await;
+          return None;
+        }
+        Some((Ok(packet), (Some(sub), None, held)))
+      },
+    ))
+  }
+
+  pub async fn request_and_decode<T>(
+    &self,
+    address: &ArcStr,
+    content: Packet,
+    headers: HeaderMap,
+  ) -> Result<T>
+  where
+    T: TryFrom<Event, Error = DataError>,
+  {
+    let reply = self.request(address, content, headers).await?;
+    match Packet::from_cbor(&reply.payload)? {
+      Either::Right(event) => Ok(T::try_from(event)?),
+      Either::Left(_) => Err(DataError::UnexpectedResponse("message".into()).into()),
+    }
+  }
+
+  // version and capabilities of the relay on address, cached for a minute
+  pub async fn get_address_info(&self, address: &ArcStr) -> Result<RelayInfo> {
+    // keyed by the resolved address, so an alias and its target share an entry
+    let resolved = self.resolve_alias(address);
+    if let Some(entry) = self.address_info.get(&resolved) {
+      let (fetched_at, info) = entry.value();
+      if fetched_at.elapsed() < ADDRESS_INFO_TTL {
+        return Ok(info.clone());
+      }
+    }
+    let packet = Packet::from(Event::InfoRequest {}.to_right())?;
+    let info: RelayInfo = self
+      .request_and_decode(address, packet, self.new_lib_header()?)
+      .await?;
+    self
+      .address_info
+      .insert(resolved, (Instant::now(), info.clone()));
+    Ok(info)
+  }
+
+  // applies ChannelCreate/ChannelDelete/ChannelRename to the routing table,
+  // returns whether the table changed
+  pub fn apply_channel_event(&self, event: Event) -> bool {
+    match event {
+      Event::ChannelCreate { channel, metadata } => {
+        self.channels.insert(channel, metadata);
+        true
+      }
+      Event::ChannelDelete { channel } => self.channels.remove(&channel).is_some(),
+      Event::ChannelRename { channel, new_name } => match self.channels.get_mut(&channel) {
+        Some(mut metadata) => {
+          metadata.insert(arcstr::literal!("name"), new_name);
+          true
+        }
+        None => false,
+      },
+      _ => false,
+    }
   }
 
   pub fn unsub(&self, target: &ArcStr) {
@@ -217,31 +1212,50 @@ impl Server {
     // Maybe, one day rustc could be clever enough to conclude that packet is
     // right(Event)
     match packet.expect_right("Unreachable") {
-      Event::RequestImage { id } => {
+      Event::RequestImage { id, .. } => {
         use crate::res::RES;
-        let url = match RES.get_photo_url(&id).await {
+        let url = match RES.get_photo_url_cached(&id).await {
           Some(s) => s,
           None => {
             info!("{}", t!("log.image-not-found"));
             return Ok(());
           }
         };
-        let event: Event = Event::RespondImage { id, url };
-        let payload = Packet::from(event.to_right())?.to_cbor()?;
-
-        let reply = next
-          .reply
-          .ok_or_else(|| eyre!("No reply subject to reply to"))?;
-        SERVER
-          .client
-          .publish(reply, bytes::Bytes::from(payload))
-          .await
-          .map_err(|e| eyre!(e))?;
+        let event: Event = Event::RespondImage {
+          id,
+          url,
+          platform: (*SERVER.platform).clone(),
+        };
+        Server::reply_lib_message(next.reply, event).await
+      }
+      Event::InfoRequest {} => {
+        let event = Event::InfoResponse {
+          version: env!("CARGO_PKG_VERSION").into(),
+          supported_events: SUPPORTED_EVENTS.iter().map(|e| ArcStr::from(*e)).collect(),
+          max_packet_bytes: MAX_PACKET_BYTES,
+        };
+        Server::reply_lib_message(next.reply, event).await
+      }
+      event @ (Event::ChannelCreate { .. }
+      | Event::ChannelDelete { .. }
+      | Event::ChannelRename { .. }) => {
+        SERVER.apply_channel_event(event);
         Ok(())
       }
       _ => Ok(()),
     }
   }
+
+  async fn reply_lib_message(reply: Option<String>, event: Event) -> Result<()> {
+    let payload = Packet::from(event.to_right())?.to_cbor()?;
+    let reply = reply.ok_or_else(|| eyre!("No reply subject to reply to"))?;
+    SERVER
+      .client
+      .publish(reply, bytes::Bytes::from(payload))
+      .await
+      .map_err(|e| eyre!(e))?;
+    Ok(())
+  }
 }
 
 pub trait HeaderMapExt {
@@ -280,3 +1294,123 @@ impl HeaderMapExt for HeaderMap {
     contains_lib && !contains_cid
   }
 }
+
+#[cfg(test)]
+mod test {
+  use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+  };
+
+  use crate::{
+    data::{
+      events::Event,
+      message::{Message, Profile},
+      platforms::PLATFORM_QQ,
+    },
+    server::{BreakerPass, BreakerState, CircuitBreaker, CircuitBreakerConfig, SERVER},
+    EitherExt,
+  };
+
+  #[test]
+  fn subscription_filter() {
+    let filter = SERVER
+      .subscribe_to_channel("target".into(), "address".into())
+      .filter_channel("general".into())
+      .filter_event_types(vec!["message", "revoke"])
+      .filter;
+    let profile = || Profile {
+      id: Vec::from("user"),
+      username: None,
+      nick: None,
+    };
+    let message = Message::new(profile(), 1, vec![], "general", PLATFORM_QQ);
+    assert!(filter.accepts(&message.to_left()));
+    let message = Message::new(profile(), 1, vec![], "random", PLATFORM_QQ);
+    assert!(!filter.accepts(&message.to_left()));
+    let revoke = Event::revoke(Vec::from("id"), "general");
+    assert!(filter.accepts(&revoke.to_right()));
+    let echo = Event::RequestEcho {
+      name: "echo".into(),
+    };
+    assert!(!filter.accepts(&echo.to_right()));
+  }
+
+  #[test]
+  fn channel_lifecycle() {
+    let mut metadata = HashMap::new();
+    metadata.insert("name".into(), "general".into());
+    let create = Event::ChannelCreate {
+      channel: "lifecycle".into(),
+      metadata,
+    };
+    let strw = serde_cbor::to_vec(&create).unwrap();
+    let create = serde_cbor::from_slice::<Event>(&strw).unwrap();
+    assert!(SERVER.apply_channel_event(create));
+    assert_eq!(
+      SERVER.channels.get("lifecycle").unwrap().get("name").unwrap(),
+      "general"
+    );
+    let rename = Event::ChannelRename {
+      channel: "lifecycle".into(),
+      new_name: "random".into(),
+    };
+    assert!(SERVER.apply_channel_event(rename));
+    assert_eq!(
+      SERVER.channels.get("lifecycle").unwrap().get("name").unwrap(),
+      "random"
+    );
+    let delete = Event::ChannelDelete {
+      channel: "lifecycle".into(),
+    };
+    assert!(SERVER.apply_channel_event(delete));
+    assert!(!SERVER.channels.contains_key("lifecycle"));
+    let delete = Event::ChannelDelete {
+      channel: "lifecycle".into(),
+    };
+    assert!(!SERVER.apply_channel_event(delete));
+  }
+
+  #[test]
+  fn circuit_breaker() {
+    let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+      failure_threshold: 2,
+      open_duration: Duration::from_secs(10),
+      half_open_probe_interval: Duration::from_secs(5),
+    });
+    let now = Instant::now();
+    breaker.on_failure(now);
+    assert!(breaker.try_pass(now).is_ok());
+    breaker.on_failure(now);
+    assert!(breaker.try_pass(now).is_err());
+    // one probe after open_duration
+    let later = now + Duration::from_secs(10);
+    assert!(breaker.try_pass(later).is_ok());
+    assert!(breaker.try_pass(later).is_err());
+    breaker.on_failure(later);
+    assert!(breaker.try_pass(later + Duration::from_secs(4)).is_err());
+    assert!(breaker.try_pass(later + Duration::from_secs(5)).is_ok());
+    breaker.on_success();
+    assert_eq!(
+      *breaker.state.lock().unwrap(),
+      BreakerState::Closed { failures: 0 }
+    );
+  }
+
+  #[test]
+  fn breaker_pass_dropped() {
+    let breaker = Arc::new(CircuitBreaker::new(CircuitBreakerConfig {
+      failure_threshold: 1,
+      open_duration: Duration::ZERO,
+      half_open_probe_interval: Duration::from_secs(5),
+    }));
+    breaker.on_failure(Instant::now());
+    // the probe is dropped, e.g. by a timeout of the caller
+    drop(BreakerPass::new(breaker.clone()).unwrap());
+    assert!(matches!(
+      *breaker.state.lock().unwrap(),
+      BreakerState::Open { .. }
+    ));
+  }
+}